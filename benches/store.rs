@@ -1,21 +1,23 @@
 #[macro_use]
 extern crate criterion;
 
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
+use std::sync::{Arc, Barrier};
+use std::thread;
 use tempfile::TempDir;
 
-use criterion::black_box;
 use criterion::Criterion;
 
-fn kv_store_benchmark(c: &mut Criterion) {
-    c.bench_function("KvStore", |b| {
+fn engine_benchmark<E: KvsEngine>(c: &mut Criterion, name: &str) {
+    c.bench_function(name, |b| {
         b.iter_batched_ref(
             || {
                 let temp_dir =
                     TempDir::new().expect("unable to create temporary working directory");
-                KvStore::open(temp_dir.path()).unwrap()
+                // Keep the directory alive for as long as the store is used.
+                (E::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |ref mut store| {
+            |(ref mut store, _)| {
                 for i in 0..1000 {
                     store
                         .set(format!("key-{}", i), format!("value-{}", i))
@@ -30,5 +32,90 @@ fn kv_store_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, kv_store_benchmark);
+fn kv_store_benchmark(c: &mut Criterion) {
+    engine_benchmark::<KvStore>(c, "KvStore");
+}
+
+fn sled_benchmark(c: &mut Criterion) {
+    engine_benchmark::<SledKvsEngine>(c, "SledKvsEngine");
+}
+
+// Reads a fixed number of keys split across a growing number of threads.
+fn concurrent_get_benchmark(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..1000 {
+        store
+            .set(format!("key-{}", i), format!("value-{}", i))
+            .unwrap();
+    }
+
+    c.bench_function_over_inputs(
+        "KvStore concurrent get",
+        move |b, &&threads| {
+            b.iter(|| {
+                let barrier = Arc::new(Barrier::new(threads));
+                let handles: Vec<_> = (0..threads)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        let barrier = barrier.clone();
+                        thread::spawn(move || {
+                            barrier.wait();
+                            for i in (thread_id..1000).step_by(threads) {
+                                store.get(format!("key-{}", i)).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        &[1, 2, 4, 8],
+    );
+}
+
+// Writes a fixed number of keys split across a growing number of threads,
+// syncing every write.
+fn concurrent_set_benchmark(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+
+    c.bench_function_over_inputs(
+        "KvStore concurrent synced set",
+        move |b, &&threads| {
+            b.iter(|| {
+                let barrier = Arc::new(Barrier::new(threads));
+                let handles: Vec<_> = (0..threads)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        let barrier = barrier.clone();
+                        thread::spawn(move || {
+                            barrier.wait();
+                            for i in (thread_id..100).step_by(threads) {
+                                store
+                                    .set(format!("key-{}", i), format!("value-{}", i))
+                                    .unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        &[1, 2, 4, 8],
+    );
+}
+
+criterion_group!(
+    benches,
+    kv_store_benchmark,
+    sled_benchmark,
+    concurrent_get_benchmark,
+    concurrent_set_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// WriteBatch collects sets and removes of multiple keys, applied atomically
+/// through `KvsEngine::write_batch`.
+///
+/// A batch might expect keys to hold given values, in which case it is only
+/// applied if all of them do, failing with `KvStoreError::ConditionFailed`
+/// otherwise.
+///
+/// # Example
+///
+/// ``` rust
+/// use kvs::{KvStore, WriteBatch};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove("key1".to_owned());
+/// batch.set("key2".to_owned(), "value2".to_owned());
+/// store.write_batch(batch).unwrap();
+///
+/// assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+/// assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+    // Values keys are expected to hold, `None` standing for an absent key.
+    pub(crate) expected: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// Single write of a `WriteBatch`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+impl WriteBatch {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Set the value for the given key.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+    }
+
+    /// Remove the value of the given key. The whole batch fails if the key
+    /// does not exist at the time the batch is applied.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push(BatchOp::Remove(key.into()));
+    }
+
+    /// Only apply the batch if the given key holds the expected value, with
+    /// `None` standing for an absent key.
+    pub fn expect(&mut self, key: impl Into<Vec<u8>>, expected: Option<Vec<u8>>) {
+        self.expected.push((key.into(), expected));
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
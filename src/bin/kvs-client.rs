@@ -3,9 +3,9 @@ use clap::{Arg, App, AppSettings, SubCommand};
 use std::io::prelude::*;
 use std::net::TcpStream;
 use log::{info};
-use env_logger;
 
 use kvs::network::{ Req, Resp, SuccResp};
+use kvs::WatchEvent;
 
 fn main() -> Result<()>{
     env_logger::init();
@@ -33,11 +33,88 @@ fn main() -> Result<()>{
                     .about("set key with the given value")
                     .arg(Arg::with_name("KEY").required(true))
                     .arg(Arg::with_name("VALUE").required(true))
+                    .arg(Arg::with_name("if-absent")
+                         .long("if-absent")
+                         .help("only set the key if it does not exist yet"))
+                    .arg(Arg::with_name("ttl")
+                         .long("ttl")
+                         .takes_value(true)
+                         .conflicts_with("if-absent")
+                         .help("expire the key after the given number of seconds"))
         )
         .subcommand(SubCommand::with_name("rm")
                     .about("remove value for the given key")
                     .arg(Arg::with_name("KEY").required(true))
         )
+        .subcommand(SubCommand::with_name("scan")
+                    .about("list all keys and values with keys starting with the given prefix")
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .takes_value(true)
+                         .default_value(""))
+        )
+        .subcommand(SubCommand::with_name("cas")
+                    .about("set key to the new value, or remove it without one, if it holds the expected value")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("expected")
+                         .long("expected")
+                         .takes_value(true)
+                         .help("expected value, the key is expected to be absent without one"))
+                    .arg(Arg::with_name("new")
+                         .long("new")
+                         .takes_value(true)
+                         .help("new value, the key is removed without one"))
+        )
+        .subcommand(SubCommand::with_name("incr")
+                    .setting(AppSettings::AllowNegativeNumbers)
+                    .about("add to the number stored at the given key, printing the result")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("DELTA").default_value("1"))
+        )
+        .subcommand(SubCommand::with_name("decr")
+                    .setting(AppSettings::AllowNegativeNumbers)
+                    .about("subtract from the number stored at the given key, printing the result")
+                    .arg(Arg::with_name("KEY").required(true))
+                    .arg(Arg::with_name("DELTA").default_value("1"))
+        )
+        .subcommand(SubCommand::with_name("tx")
+                    .about("apply all sets and removes atomically, if all keys hold their expected values; removes are applied after sets")
+                    .arg(Arg::with_name("expect")
+                         .long("expect")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY=VALUE")
+                         .help("expect the key to hold the value"))
+                    .arg(Arg::with_name("expect-absent")
+                         .long("expect-absent")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY")
+                         .help("expect the key to be absent"))
+                    .arg(Arg::with_name("set")
+                         .long("set")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY=VALUE")
+                         .help("set the key to the value"))
+                    .arg(Arg::with_name("rm")
+                         .long("rm")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY")
+                         .help("remove the key"))
+        )
+        .subcommand(SubCommand::with_name("watch")
+                    .about("print all writes to keys starting with the given prefix as they happen")
+                    .arg(Arg::with_name("prefix")
+                         .long("prefix")
+                         .takes_value(true)
+                         .default_value(""))
+        )
         .get_matches();
 
     let addr = matches.value_of("addr").unwrap();
@@ -50,7 +127,7 @@ fn main() -> Result<()>{
             // clap enforces KEY argument.
             let key = matches.value_of("KEY").unwrap();
 
-            Req::Get(key.to_string())
+            Req::Get(key.into())
         }
         ("set", Some(matches)) => {
             // clap enforces KEY argument.
@@ -58,49 +135,170 @@ fn main() -> Result<()>{
             // clap enforces VALUE argument.
             let value = matches.value_of("VALUE").unwrap();
 
-            Req::Set(key.to_string(), value.to_string())
+            if matches.is_present("if-absent") {
+                Req::CompareAndSwap(key.into(), None, Some(value.into()))
+            } else if let Some(ttl) = matches.value_of("ttl") {
+                let ttl = ttl
+                    .parse::<u64>()
+                    .map_err(|_| ClientError::InvalidArgument("TTL must be a number of seconds".to_string()))?;
+
+                Req::SetWithTtl(key.into(), value.into(), std::time::Duration::from_secs(ttl))
+            } else {
+                Req::Set(key.into(), value.into())
+            }
         }
         ("rm", Some(matches)) => {
             // clap enforces KEY argument.
             let key = matches.value_of("KEY").unwrap();
 
-            Req::Remove(key.to_string())
+            Req::Remove(key.into())
+        }
+        ("scan", Some(matches)) => {
+            // clap provides a default prefix.
+            let prefix = matches.value_of("prefix").unwrap();
+
+            Req::ScanPrefix(prefix.into())
+        }
+        ("cas", Some(matches)) => {
+            // clap enforces KEY argument.
+            let key = matches.value_of("KEY").unwrap();
+            let expected = matches.value_of("expected").map(Vec::from);
+            let new = matches.value_of("new").map(Vec::from);
+
+            Req::CompareAndSwap(key.into(), expected, new)
+        }
+        (name @ "incr", Some(matches)) | (name @ "decr", Some(matches)) => {
+            // clap enforces KEY argument.
+            let key = matches.value_of("KEY").unwrap();
+            // clap provides a default delta.
+            let delta = matches
+                .value_of("DELTA")
+                .unwrap()
+                .parse::<i64>()
+                .map_err(|_| ClientError::InvalidArgument("DELTA must be a number".to_string()))?;
+
+            if name == "decr" {
+                let delta = delta
+                    .checked_neg()
+                    .ok_or_else(|| ClientError::InvalidArgument("DELTA out of range".to_string()))?;
+
+                Req::Incr(key.into(), delta)
+            } else {
+                Req::Incr(key.into(), delta)
+            }
+        }
+        ("tx", Some(matches)) => {
+            let pair = |arg: &str| {
+                let mut parts = arg.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(k), Some(v)) => Ok((k.to_string(), v.to_string())),
+                    _ => Err(ClientError::InvalidArgument(format!("expected KEY=VALUE, got '{}'", arg))),
+                }
+            };
+
+            let mut batch = kvs::WriteBatch::new();
+            for arg in matches.values_of("expect").into_iter().flatten() {
+                let (k, v) = pair(arg)?;
+                batch.expect(k, Some(v.into_bytes()));
+            }
+            for k in matches.values_of("expect-absent").into_iter().flatten() {
+                batch.expect(k, None);
+            }
+            for arg in matches.values_of("set").into_iter().flatten() {
+                let (k, v) = pair(arg)?;
+                batch.set(k, v);
+            }
+            for k in matches.values_of("rm").into_iter().flatten() {
+                batch.remove(k);
+            }
+
+            Req::Transaction(batch)
+        }
+        ("watch", Some(matches)) => {
+            // clap provides a default prefix.
+            let prefix = matches.value_of("prefix").unwrap();
+
+            Req::Watch(prefix.into())
         }
         _ => unreachable!(),
     };
 
-    let serialized = serde_json::to_string(&req)?;
+    let serialized = bincode::serialize(&req)?;
 
-    stream.write_all(serialized.as_bytes())?;
+    stream.write_all(&serialized)?;
 
-    let mut resp_stream =
-        serde_json::Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Resp>();
-
-    let resp = resp_stream
-        .next()
-        .ok_or_else(|| ClientError::ClosedStream)??;
+    let mut reader = std::io::BufReader::new(stream);
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
 
     let key_not_found = "Key not found".to_string();
 
-    match resp {
-        // Test suit forces us to print "Key not found" on /remove/ and /get/,
-        // but only exit non zero for remove, not get.
-        Ok(SuccResp::Get(v)) => {
-            match v {
-                None => println!("{}", key_not_found),
-                Some(v) => println!("{}", v),
+    // A scan is answered with one response per key value pair, a watch with
+    // one response per write.
+    loop {
+        let resp: Resp = bincode::deserialize_from(&mut reader).map_err(|e| match *e {
+            bincode::ErrorKind::Io(ref c) if c.kind() == std::io::ErrorKind::UnexpectedEof => ClientError::ClosedStream,
+            _ => ClientError::Bincode(e),
+        })?;
+
+        return match resp {
+            // Test suit forces us to print "Key not found" on /remove/ and /get/,
+            // but only exit non zero for remove, not get.
+            Ok(SuccResp::Get(v)) => {
+                match v {
+                    None => println!("{}", key_not_found),
+                    // Values are printed as is, they are not necessarily utf8.
+                    Some(v) => {
+                        stdout.write_all(&v)?;
+                        stdout.write_all(b"\n")?;
+                    }
+                }
+
+                Ok(())
+            },
+            Ok(SuccResp::Set) | Ok(SuccResp::Remove) | Ok(SuccResp::CompareAndSwap) | Ok(SuccResp::Transaction) => {info!("success"); Ok(())},
+            Ok(SuccResp::Incr(v)) => {
+                println!("{}", v);
+
+                Ok(())
             }
+            Ok(SuccResp::ConditionFailed) => {
+                eprintln!("Condition failed");
 
-            Ok(())
-        },
-        Ok(SuccResp::Set) | Ok(SuccResp::Remove) => {info!("success"); Ok(())},
-        Err(kvs::network::Error::Server(e)) => {
-            if e == key_not_found {
-                eprintln!("{}", key_not_found)
+                Err(ClientError::ConditionFailed)
             }
+            Ok(SuccResp::Entry(k, v)) => {
+                stdout.write_all(&k)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&v)?;
+                stdout.write_all(b"\n")?;
 
-            Err(ClientError::NetworkError(kvs::network::Error::Server(e)))
-        }
+                continue;
+            }
+            Ok(SuccResp::ScanEnd) => Ok(()),
+            Ok(SuccResp::Event(event)) => {
+                let (seq, line) = match event {
+                    WatchEvent::Set { key, value, seq, .. } => (seq, [&b"set\t"[..], &key, b"\t", &value].concat()),
+                    WatchEvent::Remove { key, seq, .. } => (seq, [&b"rm\t"[..], &key].concat()),
+                };
+                if let Some(seq) = seq {
+                    write!(stdout, "{}\t", seq)?;
+                }
+                stdout.write_all(&line)?;
+                stdout.write_all(b"\n")?;
+                // Events are printed as they happen.
+                stdout.flush()?;
+
+                continue;
+            }
+            Err(kvs::network::Error::Server(e)) => {
+                if e == key_not_found {
+                    eprintln!("{}", key_not_found)
+                }
+
+                Err(ClientError::NetworkError(kvs::network::Error::Server(e)))
+            }
+        };
     }
 }
 
@@ -112,8 +310,10 @@ pub enum ClientError {
     KvStore(kvs::KvStoreError),
     ClosedStream,
     Io(std::io::Error),
-    SerdeJson(serde_json::error::Error),
+    Bincode(bincode::Error),
     NetworkError(kvs::network::Error),
+    ConditionFailed,
+    InvalidArgument(String),
 }
 
 impl From<kvs::KvStoreError> for ClientError {
@@ -128,9 +328,9 @@ impl From<std::io::Error> for ClientError {
     }
 }
 
-impl From<serde_json::error::Error> for ClientError {
-    fn from(err: serde_json::error::Error) -> ClientError {
-        ClientError::SerdeJson(err)
+impl From<bincode::Error> for ClientError {
+    fn from(err: bincode::Error) -> ClientError {
+        ClientError::Bincode(err)
     }
 }
 
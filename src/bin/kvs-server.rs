@@ -1,16 +1,8 @@
 use clap::{App, AppSettings, Arg};
-use env_logger;
-use kvs::thread_pool::ThreadPool;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use log::error;
 
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-
-use std::fs;
-use std::io::Read;
-
-use kvs::network::{Req, Resp, SuccResp};
-
 fn main() -> Result<(), kvs::server::ServerError> {
     env_logger::init();
 
@@ -25,7 +17,7 @@ fn main() -> Result<(), kvs::server::ServerError> {
                 .takes_value(true)
                 .long("thread-pool")
                 .help("specify thread pool strategy")
-                .possible_values(&vec!["shared", "naive", "rayon"])
+                .possible_values(&["shared", "naive", "rayon"])
                 .default_value("shared"),
         )
         .arg(
@@ -35,26 +27,44 @@ fn main() -> Result<(), kvs::server::ServerError> {
                 .help("specify the address to listen on")
                 .default_value("[::1]:4000"),
         )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .help("specify the storage engine")
+                .possible_values(&["kvs", "sled"])
+                .default_value("kvs"),
+        )
         .get_matches();
 
     error!(env!("CARGO_PKG_VERSION"));
 
+    let engine = matches.value_of("engine").unwrap();
+    error!("Using engine '{}'.", engine);
+
     let addr = matches.value_of("addr").unwrap();
     error!("Listening on '{}'.", addr);
 
-    match matches.value_of("thread-pool").unwrap() {
-        "shared" => {
-            kvs::server::Server::<kvs::KvStore, kvs::thread_pool::SharedQueueThreadPool>::new(
-                std::path::Path::new("./"),
-                10,
-            )
-            .listen(addr.to_string())
-        }
-        "rayon" => kvs::server::Server::<kvs::KvStore, kvs::thread_pool::RayonThreadPool>::new(
-            std::path::Path::new("./"),
-            10,
-        )
-        .listen(addr.to_string()),
-        _ => unimplemented!(),
+    let path = std::path::Path::new("./");
+    // The server might be killed at any time, so sled has to flush every
+    // write before it is acknowledged. Writes to a KvStore only need to reach
+    // the operating system.
+    match (engine, matches.value_of("thread-pool").unwrap()) {
+        ("kvs", "naive") => run::<_, NaiveThreadPool>(KvStore::open(path)?, addr),
+        ("kvs", "shared") => run::<_, SharedQueueThreadPool>(KvStore::open(path)?, addr),
+        ("kvs", "rayon") => run::<_, RayonThreadPool>(KvStore::open(path)?, addr),
+        ("sled", "naive") => run::<_, NaiveThreadPool>(SledKvsEngine::open_with(path, true)?, addr),
+        ("sled", "shared") => run::<_, SharedQueueThreadPool>(SledKvsEngine::open_with(path, true)?, addr),
+        ("sled", "rayon") => run::<_, RayonThreadPool>(SledKvsEngine::open_with(path, true)?, addr),
+        // Both values are restricted by clap.
+        _ => unreachable!(),
     }
 }
+
+fn run<E, P>(db: E, addr: &str) -> Result<(), kvs::server::ServerError>
+where
+    E: KvsEngine + Sync,
+    P: ThreadPool,
+{
+    kvs::server::Server::<E, P>::with_engine(db, 10)?.listen(addr.to_string())
+}
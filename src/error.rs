@@ -1,3 +1,6 @@
+// failure_derive generates its impls inside a named const.
+#![allow(non_local_definitions)]
+
 
 
 /// Result type returned by the KvStore library.
@@ -60,6 +63,22 @@ pub enum KvStoreError {
         c: serde_json::error::Error,
     },
 
+    /// Failure when serializing binary data.
+    #[fail(display = "failed to serialize binary data")]
+    BinarySerializationFailure {
+        /// Underlying bincode Error.
+        #[cause]
+        c: bincode::Error,
+    },
+
+    /// Failure when deserializing binary data.
+    #[fail(display = "failed to deserialize binary data")]
+    BinaryDeserializationFailure {
+        /// Underlying bincode Error.
+        #[cause]
+        c: bincode::Error,
+    },
+
     /// Failure writing to file.
     #[fail(display = "failed to write to file")]
     WriteToFileFailure {
@@ -68,6 +87,44 @@ pub enum KvStoreError {
         c: std::io::Error,
     },
 
+    /// Failure reading from file.
+    #[fail(display = "failed to read from file")]
+    ReadFromFileFailure {
+        /// Underlying io Error.
+        #[cause]
+        c: std::io::Error,
+    },
+
+    /// Failure listing directory.
+    #[fail(display = "failed to read directory {}", name)]
+    ReadDirFailure {
+        /// Underlying io Error.
+        #[cause]
+        c: std::io::Error,
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Failure creating directory.
+    #[fail(display = "failed to create directory {}", name)]
+    CreateDirFailure {
+        /// Underlying io Error.
+        #[cause]
+        c: std::io::Error,
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Failure removing file.
+    #[fail(display = "failed to remove file {}", name)]
+    FileRemoveFailure {
+        /// Underlying io Error.
+        #[cause]
+        c: std::io::Error,
+        /// Name of the file.
+        name: String,
+    },
+
     /// Failure seeking file.
     #[fail(display = "failed to seek file")]
     SeekFileFailure {
@@ -76,10 +133,182 @@ pub enum KvStoreError {
         c: std::io::Error,
     },
 
+    /// Failure reaching the background compaction thread.
+    #[fail(display = "compaction thread stopped")]
+    CompactionStopped,
+
+    /// Record cut short at the end of the log by an interrupted write.
+    #[fail(display = "torn write in log segment {} at offset {}", segment, offset)]
+    TornWrite {
+        /// Id of the segment.
+        segment: u64,
+        /// Offset of the record within the segment.
+        offset: u64,
+    },
+
+    /// Record failing its checksum or framing.
+    #[fail(display = "corrupt record in log segment {} at offset {}", segment, offset)]
+    CorruptRecord {
+        /// Id of the segment.
+        segment: u64,
+        /// Offset of the record within the segment.
+        offset: u64,
+    },
+
+    /// Hint file failing its checksum or framing.
+    #[fail(display = "corrupt hint file of log segment {}", segment)]
+    CorruptHint {
+        /// Id of the segment.
+        segment: u64,
+    },
+
+    /// Log segment written by a newer version.
+    #[fail(display = "unsupported format version {} of log segment {}", version, segment)]
+    UnsupportedFormat {
+        /// Id of the segment.
+        segment: u64,
+        /// Format version of the segment.
+        version: u32,
+    },
+
+    /// Failure finding a log segment referenced by the index.
+    #[fail(display = "log segment {} not found", id)]
+    SegmentNotFound {
+        /// Id of the segment.
+        id: u64,
+    },
+
+    /// Failure committing a group of writes, reported to every write of the
+    /// group.
+    #[fail(display = "failed to commit write: {}", reason)]
+    CommitFailed {
+        /// Description of the underlying failure.
+        reason: String,
+    },
+
+    /// Failure of a conditional write, the key not holding the expected value.
+    #[fail(display = "Condition failed")]
+    ConditionFailed,
+
+    /// Failure parsing the value of a key as a number.
+    #[fail(display = "value of key {} is not a number", key)]
+    NotANumber {
+        /// Key holding the value.
+        key: String,
+    },
+
+    /// Number exceeding the range of a 64 bit signed integer.
+    #[fail(display = "number of key {} overflowed", key)]
+    NumberOverflow {
+        /// Key holding the number.
+        key: String,
+    },
+
+    /// Time to live reaching beyond the range of expiry times.
+    #[fail(display = "time to live of {:?} overflowed", ttl)]
+    TtlOverflow {
+        /// Requested time to live.
+        ttl: std::time::Duration,
+    },
+
+    /// Failure reading a version older than the retained history.
+    #[fail(display = "history as of sequence number {} is not retained", seq)]
+    HistoryUnavailable {
+        /// Requested sequence number.
+        seq: u64,
+    },
+
+    /// Key exceeding the maximum key size.
+    #[fail(display = "key of {} bytes exceeds the maximum of {} bytes", len, max)]
+    KeyTooLarge {
+        /// Size of the key.
+        len: usize,
+        /// Maximum key size.
+        max: usize,
+    },
+
+    /// Value exceeding the maximum value size.
+    #[fail(display = "value of {} bytes exceeds the maximum of {} bytes", len, max)]
+    ValueTooLarge {
+        /// Size of the value.
+        len: usize,
+        /// Maximum value size.
+        max: usize,
+    },
+
     /// Failure finding key.
     #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// Failure decoding stored bytes as utf8.
+    #[fail(display = "failed to decode value as utf8")]
+    Utf8Failure {
+        /// Underlying utf8 Error.
+        #[cause]
+        c: std::string::FromUtf8Error,
+    },
+
+    /// Failure opening a directory owned by a different engine.
+    #[fail(display = "directory belongs to engine {}, not {}", found, expected)]
+    WrongEngine {
+        /// Engine trying to open the directory.
+        expected: String,
+        /// Engine owning the directory.
+        found: String,
+    },
+
+    /// Failure opening a directory already opened by another store.
+    #[fail(display = "directory {} is locked by another store", name)]
+    DirectoryLocked {
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Failure locking a directory.
+    #[fail(display = "failed to lock directory {}", name)]
+    LockFailure {
+        /// Underlying io Error.
+        #[cause]
+        c: std::io::Error,
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Failure opening a directory without a store, not asked to create one.
+    #[fail(display = "no store found in directory {}", name)]
+    StoreNotFound {
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Failure opening a directory already holding a store, asked not to.
+    #[fail(display = "store already exists in directory {}", name)]
+    StoreExists {
+        /// Name of the directory.
+        name: String,
+    },
+
+    /// Options failing validation.
+    #[fail(display = "invalid options: {}", reason)]
+    InvalidOptions {
+        /// Description of the invalid option.
+        reason: String,
+    },
+
+    /// Failure writing to a store opened read-only.
+    #[fail(display = "store is opened read-only")]
+    ReadOnly,
+
+    /// Failure writing to a store after a thread panicked while writing to
+    /// it, or a failed write could not be cut off the log, leaving the state
+    /// of the log unknown until the store is reopened.
+    #[fail(display = "store is poisoned by a failed write, reopen it")]
+    Poisoned,
+
+    /// Watch ended for falling too far behind the writes to its keys.
+    #[fail(display = "watch fell behind the writes to its keys")]
+    WatchLagged,
+
     /// Sled error.
     #[fail(display = "Sled page cache error")]
     PageCache(sled::Error)
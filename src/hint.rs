@@ -0,0 +1,97 @@
+use crate::error::{KvStoreError, Result};
+use crate::record;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// Magic bytes at the start of every hint file.
+const MAGIC: [u8; 4] = *b"KVSH";
+
+/// Format version written to new hint files.
+const FORMAT_VERSION: u32 = 1;
+
+/// Length of the hint file header: magic followed by the format version.
+const HEADER_LEN: usize = 8;
+
+/// Hint lists the records of a compacted segment, thereby allowing the
+/// segment to be indexed without reading it. A hint file holds a single hint
+/// framed as a record, covering the whole hint with a checksum.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct Hint {
+    /// Length of the segment, guarding against a hint not matching its
+    /// segment.
+    pub(crate) len: u64,
+    /// Records of the segment in order.
+    pub(crate) entries: Vec<HintEntry>,
+}
+
+/// HintEntry describes a single record of a compacted segment.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct HintEntry {
+    /// Key of the record, empty for the sequence marker.
+    pub(crate) key: Vec<u8>,
+    /// Offset of the record within the segment.
+    pub(crate) offset: u64,
+    /// Length of the record including its header.
+    pub(crate) len: u64,
+    /// Sequence number of the write of the record.
+    pub(crate) seq: u64,
+    /// Expiry of the value set by the record.
+    pub(crate) expires: Option<u64>,
+    pub(crate) kind: HintKind,
+}
+
+/// Kind of the record described by a `HintEntry`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub(crate) enum HintKind {
+    /// Record setting its key.
+    Set,
+    /// Tombstone of its key.
+    Remove,
+    /// Marker carrying the last sequence number over to compacted segments.
+    Sequence,
+}
+
+/// Writes the given hint to `path`, syncing it before returning.
+pub(crate) fn write(path: &Path, hint: &Hint) -> Result<()> {
+    let payload = bincode::serialize(hint).map_err(|c| KvStoreError::BinarySerializationFailure { c })?;
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&record::encode(&payload));
+
+    let mut file = std::fs::File::create(path).map_err(|c| KvStoreError::OpenFileFailure {
+        c,
+        name: path.display().to_string(),
+    })?;
+    file.write_all(&buf)
+        .and_then(|()| file.sync_all())
+        .map_err(|c| KvStoreError::WriteToFileFailure { c })
+}
+
+/// Reads the hint of the segment with the given id from `path`, returning
+/// `None` if there is none.
+pub(crate) fn read(path: &Path, segment: u64) -> Result<Option<Hint>> {
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref c) if c.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(c) => return Err(KvStoreError::ReadFromFileFailure { c }),
+    };
+
+    let corrupt = || KvStoreError::CorruptHint { segment };
+
+    // Hint files of a later version are ignored like corrupt ones, the
+    // segment can always be replayed instead.
+    if buf.len() < HEADER_LEN
+        || buf[..MAGIC.len()] != MAGIC
+        || buf[MAGIC.len()..HEADER_LEN] != FORMAT_VERSION.to_le_bytes()
+    {
+        return Err(corrupt());
+    }
+
+    let payload = record::decode(segment, HEADER_LEN as u64, &buf[HEADER_LEN..]).map_err(|_| corrupt())?;
+    let hint = bincode::deserialize(payload).map_err(|_| corrupt())?;
+
+    Ok(Some(hint))
+}
@@ -3,8 +3,12 @@
 //! # KvStore
 //! `KvStore` packages a key value store.
 
+pub use batch::WriteBatch;
 pub use error::{KvStoreError, Result};
-pub use store::{KvStore};
+pub use store::{KvStore, KvStoreOptions, Snapshot, SyncPolicy, Version, WriteOptions};
+pub use sled_engine::SledKvsEngine;
+pub use transaction::Transaction;
+pub use watch::{Watch, WatchEvent};
 
 #[macro_use]
 extern crate failure_derive;
@@ -18,20 +22,190 @@ pub mod network;
 /// Implementation of a basic thread pool.
 pub mod thread_pool;
 
+mod batch;
+
+mod hint;
+
+mod manifest;
+
+mod record;
+
 mod store;
 
+mod transaction;
+
+mod watch;
+
+mod sled_engine;
+
 /// Server implementation.
 pub mod server;
 
+/// Iterator over key value pairs in ascending key order, as returned by
+/// `KvsEngine::scan_bytes`.
+pub type ScanBytes = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Iterator over keys in ascending order, as returned by
+/// `KvsEngine::keys_bytes`.
+pub type KeysBytes = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
+
+/// Iterator over key value pairs in ascending key order, as returned by
+/// `KvsEngine::scan`.
+pub type Scan = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Iterator over keys in ascending order, as returned by `KvsEngine::keys`.
+pub type Keys = Box<dyn Iterator<Item = Result<String>> + Send>;
+
 /// KvsEngine represents the storage interface used by KvsServer.
+///
+/// Keys and values are arbitrary bytes. The methods taking and returning
+/// `String`s are a convenience layer on top, failing with
+/// `KvStoreError::Utf8Failure` on stored bytes that are not valid utf8.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Open a database.
     fn open(path: &std::path::Path) -> Result<Self>;
     /// Set the value for the given key.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
     /// Get the value of the given key.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
     /// Remove the value of the given key.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Apply all writes of the given batch. Either all or none of them
+    /// survive a crash.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Set the given key to `new`, or remove it if `new` is `None`, but only
+    /// if it currently holds `expected`, with `None` standing for an absent
+    /// key. Fails with `KvStoreError::ConditionFailed` otherwise.
+    ///
+    /// A value replacing another one keeps its expiry, if any, whereas a
+    /// value set on an absent key never expires.
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()>;
+    /// Set the value for the given key, expiring after the given duration.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: std::time::Duration) -> Result<()>;
+    /// Add `delta` to the number stored at the given key, starting from zero
+    /// for an absent key, and return the result. The number keeps the expiry
+    /// of the value it replaces.
+    fn increment_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let not_a_number = || KvStoreError::NotANumber {
+            key: String::from_utf8_lossy(&key).into_owned(),
+        };
+
+        loop {
+            let current = self.get_bytes(key.clone())?;
+            let value = match &current {
+                Some(v) => std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.parse::<i64>().ok())
+                    .ok_or_else(not_a_number)?,
+                None => 0,
+            };
+            let next = value.checked_add(delta).ok_or_else(|| KvStoreError::NumberOverflow {
+                key: String::from_utf8_lossy(&key).into_owned(),
+            })?;
+
+            match self.compare_and_swap_bytes(key.clone(), current, Some(next.to_string().into_bytes())) {
+                Ok(()) => return Ok(next),
+                // Lost against a concurrent write, retry based on its value.
+                Err(KvStoreError::ConditionFailed) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Iterate over at most `limit` key value pairs, starting at key `start`
+    /// and stopping before key `end`.
+    ///
+    /// The iterator does not operate on a snapshot. Writes racing with the
+    /// iteration might or might not be observed.
+    fn scan_bytes(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>, limit: Option<usize>) -> Result<ScanBytes>;
+    /// Iterate over all key value pairs with keys starting with `prefix`.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ScanBytes> {
+        let end = prefix_end(&prefix);
+        self.scan_bytes(Some(prefix), end, None)
+    }
+    /// Iterate over all keys.
+    fn keys_bytes(&self) -> Result<KeysBytes>;
+    /// Watch all writes to keys starting with `prefix`, committed from now
+    /// on.
+    ///
+    /// Writes are buffered until read, thus a watch should be read from
+    /// continuously or dropped. A watch falling too far behind ends with
+    /// `KvStoreError::WatchLagged`.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watch>;
+
+    /// Set the value for the given key.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Get the value of the given key.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?.map(utf8).transpose()
+    }
+    /// Remove the value of the given key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    /// Set the given key to `new`, or remove it if `new` is `None`, but only
+    /// if it currently holds `expected`, with `None` standing for an absent
+    /// key. Fails with `KvStoreError::ConditionFailed` otherwise.
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+    /// Set the value for the given key unless the key already exists.
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+    /// Set the value for the given key, expiring after the given duration.
+    fn set_with_ttl(&self, key: String, value: String, ttl: std::time::Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+    /// Add `delta` to the number stored at the given key, starting from zero
+    /// for an absent key, and return the result.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.increment_bytes(key.into_bytes(), delta)
+    }
+    /// Iterate over at most `limit` key value pairs, starting at key `start`
+    /// and stopping before key `end`.
+    fn scan(&self, start: Option<String>, end: Option<String>, limit: Option<usize>) -> Result<Scan> {
+        let pairs = self.scan_bytes(start.map(String::into_bytes), end.map(String::into_bytes), limit)?;
+
+        Ok(Box::new(pairs.map(|pair| {
+            let (k, v) = pair?;
+            Ok((utf8(k)?, utf8(v)?))
+        })))
+    }
+    /// Iterate over all key value pairs with keys starting with `prefix`.
+    fn scan_prefix(&self, prefix: String) -> Result<Scan> {
+        let pairs = self.scan_prefix_bytes(prefix.into_bytes())?;
+
+        Ok(Box::new(pairs.map(|pair| {
+            let (k, v) = pair?;
+            Ok((utf8(k)?, utf8(v)?))
+        })))
+    }
+    /// Iterate over all keys.
+    fn keys(&self) -> Result<Keys> {
+        Ok(Box::new(self.keys_bytes()?.map(|k| utf8(k?))))
+    }
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|c| KvStoreError::Utf8Failure { c })
 }
 
+/// Returns the smallest key greater than all keys starting with `prefix`, if
+/// there is one.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
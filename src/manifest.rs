@@ -0,0 +1,156 @@
+use crate::error::{KvStoreError, Result};
+use fs2::FileExt;
+use std::io::Write;
+
+/// Name of the file recording which engine owns a data directory.
+const ENGINE_FILE: &str = "engine";
+
+/// Name of the file locked by the store using a data directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// Claims the directory at `path` for the engine `name`.
+///
+/// The first engine to open a directory records its name, every later open by
+/// a different engine is refused, as it would otherwise silently ignore or
+/// corrupt the data already stored. Directories written before engines were
+/// recorded are attributed by the files they hold.
+pub(crate) fn claim_dir(path: &std::path::Path, name: &str) -> Result<()> {
+    let engine_path = path.join(ENGINE_FILE);
+
+    match std::fs::read_to_string(&engine_path) {
+        Ok(found) => {
+            if found.trim() != name {
+                return Err(KvStoreError::WrongEngine {
+                    expected: name.to_string(),
+                    found: found.trim().to_string(),
+                });
+            }
+
+            Ok(())
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            check_files(path, name)?;
+
+            let mut file = std::fs::File::create(&engine_path).map_err(|c| {
+                KvStoreError::OpenFileFailure {
+                    c,
+                    name: engine_path.display().to_string(),
+                }
+            })?;
+
+            file.write_all(name.as_bytes())
+                .map_err(|c| KvStoreError::WriteToFileFailure { c })?;
+
+            Ok(())
+        }
+        Err(c) => Err(KvStoreError::OpenFileFailure {
+            c,
+            name: engine_path.display().to_string(),
+        }),
+    }
+}
+
+/// Returns whether the directory at `path` is owned by any engine.
+pub(crate) fn is_claimed(path: &std::path::Path) -> bool {
+    path.join(ENGINE_FILE).is_file()
+}
+
+/// Checks that the directory at `path` is not owned by an engine other than
+/// `name`, without claiming it.
+pub(crate) fn check_dir(path: &std::path::Path, name: &str) -> Result<()> {
+    let engine_path = path.join(ENGINE_FILE);
+
+    match std::fs::read_to_string(&engine_path) {
+        Ok(found) if found.trim() != name => Err(KvStoreError::WrongEngine {
+            expected: name.to_string(),
+            found: found.trim().to_string(),
+        }),
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => check_files(path, name),
+        Err(c) => Err(KvStoreError::OpenFileFailure {
+            c,
+            name: engine_path.display().to_string(),
+        }),
+    }
+}
+
+/// Checks that the files in the unclaimed directory at `path` were not written
+/// by an engine other than `name`.
+fn check_files(path: &std::path::Path, name: &str) -> Result<()> {
+    match detect_engine(path)? {
+        Some(found) if found != name => Err(KvStoreError::WrongEngine {
+            expected: name.to_string(),
+            found: found.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the engine that wrote the files in the directory at `path`, if
+/// any.
+///
+/// sled keeps its configuration in `conf`, its snapshots in `snap.*` and large
+/// values in `blobs`. It also writes a `db` file, which is why `db` only points
+/// to the single log file of earlier versions of kvs in the absence of these.
+fn detect_engine(path: &std::path::Path) -> Result<Option<&'static str>> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(c) => {
+            return Err(KvStoreError::ReadDirFailure {
+                c,
+                name: path.display().to_string(),
+            })
+        }
+    };
+
+    let mut kvs = false;
+    for entry in entries {
+        let entry = entry.map_err(|c| KvStoreError::ReadDirFailure {
+            c,
+            name: path.display().to_string(),
+        })?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        if file_name == "conf" || file_name == "blobs" || file_name.starts_with("snap.") {
+            return Ok(Some("sled"));
+        }
+        if file_name == "db" || file_name.ends_with(".log") {
+            kvs = true;
+        }
+    }
+
+    Ok(if kvs { Some("kvs") } else { None })
+}
+
+/// Locks the directory at `path` for exclusive use, until the returned file is
+/// dropped.
+///
+/// The lock is advisory, it only keeps out other stores opening the directory,
+/// e.g. a second server started on the same directory. It is released by the
+/// operating system once the process dies.
+pub(crate) fn lock_dir(path: &std::path::Path) -> Result<std::fs::File> {
+    let lock_path = path.join(LOCK_FILE);
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|c| KvStoreError::OpenFileFailure {
+            c,
+            name: lock_path.display().to_string(),
+        })?;
+
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref c) if c.kind() == fs2::lock_contended_error().kind() => Err(KvStoreError::DirectoryLocked {
+            name: path.display().to_string(),
+        }),
+        Err(c) => Err(KvStoreError::LockFailure {
+            c,
+            name: path.display().to_string(),
+        }),
+    }
+}
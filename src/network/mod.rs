@@ -1,14 +1,35 @@
 use serde::{Deserialize, Serialize};
 
 /// Request send by the client.
+///
+/// Requests and responses are encoded with bincode, carrying keys and values
+/// as raw bytes. A connection carries a single request.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Req {
     /// Get value for given key.
-    Get(String),
+    Get(Vec<u8>),
     /// Set value for given key.
-    Set(String, String),
+    Set(Vec<u8>, Vec<u8>),
+    /// Set value for given key, expiring after the given duration. Answered
+    /// with `SuccResp::Set`.
+    SetWithTtl(Vec<u8>, Vec<u8>, std::time::Duration),
     /// Remove value for given key.
-    Remove(String),
+    Remove(Vec<u8>),
+    /// Scan all key value pairs with keys starting with the given prefix.
+    /// Answered with one `SuccResp::Entry` per pair, followed by
+    /// `SuccResp::ScanEnd`.
+    ScanPrefix(Vec<u8>),
+    /// Set key to the new value, or remove it if `None`, but only if it holds
+    /// the expected value, `None` standing for an absent key.
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    /// Add to the number stored at the given key.
+    Incr(Vec<u8>, i64),
+    /// Apply the writes of the given batch atomically, but only if all of its
+    /// expectations hold. Answered with `SuccResp::ConditionFailed` otherwise.
+    Transaction(crate::WriteBatch),
+    /// Watch all writes to keys starting with the given prefix. Answered with
+    /// one `SuccResp::Event` per write for as long as the connection is open.
+    Watch(Vec<u8>),
 }
 
 /// Response send by server.
@@ -18,11 +39,25 @@ pub type Resp = Result<SuccResp, Error>;
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SuccResp {
     /// Successful get response containing value.
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     /// Successful set response.
     Set,
     /// Successful remove response.
     Remove,
+    /// Single key value pair of a scan.
+    Entry(Vec<u8>, Vec<u8>),
+    /// End of a successful scan.
+    ScanEnd,
+    /// Successful compare and swap response.
+    CompareAndSwap,
+    /// Compare and swap not applied, the key not holding the expected value.
+    ConditionFailed,
+    /// Successful increment response containing the resulting number.
+    Incr(i64),
+    /// Successful transaction response.
+    Transaction,
+    /// Single write to a watched key.
+    Event(crate::WatchEvent),
 }
 
 /// Failure response send by server.
@@ -0,0 +1,236 @@
+use crate::error::{KvStoreError, Result};
+use std::io::Read;
+
+/// Magic bytes at the start of every log segment.
+const MAGIC: [u8; 4] = *b"KVSL";
+
+/// Format version written to new log segments. Records hold bincode encoded
+/// commands along with the sequence number and time of their write.
+pub(crate) const FORMAT_VERSION: u32 = 3;
+
+/// Format version of segments holding bincode encoded commands only.
+pub(crate) const BINARY_FORMAT_VERSION: u32 = 2;
+
+/// Format version of segments holding serde_json encoded commands, limited to
+/// utf8 keys and values.
+pub(crate) const JSON_FORMAT_VERSION: u32 = 1;
+
+/// Format version of segments written before segments had a header. Those hold
+/// bare serde_json records without any framing.
+pub(crate) const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// Length of the segment header: magic followed by the format version.
+pub(crate) const SEGMENT_HEADER_LEN: u64 = 8;
+
+/// Length of the record header: payload length followed by the CRC32 of the
+/// payload.
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// Returns the header written at the start of every new log segment.
+pub(crate) fn segment_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(SEGMENT_HEADER_LEN as usize);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Returns the format version of a segment given its leading bytes.
+pub(crate) fn segment_version(segment: u64, header: &[u8]) -> Result<u32> {
+    // An empty segment or a torn segment header, both only ever written by the
+    // current format.
+    if header.len() < SEGMENT_HEADER_LEN as usize {
+        let prefix = std::cmp::min(header.len(), MAGIC.len());
+        if header[..prefix] == MAGIC[..prefix] {
+            return Ok(FORMAT_VERSION);
+        }
+
+        return Ok(LEGACY_FORMAT_VERSION);
+    }
+
+    if header[..MAGIC.len()] != MAGIC {
+        return Ok(LEGACY_FORMAT_VERSION);
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[MAGIC.len()..SEGMENT_HEADER_LEN as usize]);
+    let version = u32::from_le_bytes(version);
+
+    if version > FORMAT_VERSION {
+        return Err(KvStoreError::UnsupportedFormat { segment, version });
+    }
+
+    Ok(version)
+}
+
+/// Frames the given payload as a record.
+pub(crate) fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Returns the payload of the given record, verifying its length and checksum.
+pub(crate) fn decode(segment: u64, offset: u64, record: &[u8]) -> Result<&[u8]> {
+    let corrupt = || KvStoreError::CorruptRecord { segment, offset };
+
+    if record.len() < RECORD_HEADER_LEN as usize {
+        return Err(corrupt());
+    }
+
+    let (len, crc) = parse_header(&record[..RECORD_HEADER_LEN as usize]);
+    let payload = &record[RECORD_HEADER_LEN as usize..];
+
+    if payload.len() as u64 != len || checksum(payload) != crc {
+        return Err(corrupt());
+    }
+
+    Ok(payload)
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn parse_header(header: &[u8]) -> (u64, u32) {
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..8]);
+
+    (u32::from_le_bytes(len) as u64, u32::from_le_bytes(crc))
+}
+
+/// Record read from a log segment.
+pub(crate) struct Record {
+    /// Offset of the record within the segment.
+    pub(crate) offset: u64,
+    /// Length of the record including its header.
+    pub(crate) len: u64,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// RecordReader reads all records of a segment in order, as done when
+/// replaying the log.
+///
+/// A record cut short by the end of the last segment is reported as
+/// `TornWrite`, as it is the result of the process dying half way through a
+/// write. Any other record failing its checks is reported as `CorruptRecord`,
+/// given that sealed segments are synced before a new one is started.
+pub(crate) struct RecordReader<R> {
+    reader: R,
+    segment: u64,
+    // Offset of the next record.
+    offset: u64,
+    // Length of the segment.
+    len: u64,
+    // Whether this is the last segment of the log.
+    last: bool,
+    done: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// Returns a reader over the records of a segment of the given length,
+    /// with `reader` positioned right after the segment header.
+    pub(crate) fn new(reader: R, segment: u64, len: u64, last: bool) -> Self {
+        // A segment cut short within its header has no records to read, but
+        // still needs to be reported.
+        let offset = if len > 0 && len < SEGMENT_HEADER_LEN {
+            0
+        } else {
+            SEGMENT_HEADER_LEN
+        };
+
+        RecordReader::resume(reader, segment, offset, len, last)
+    }
+
+    /// Returns a reader over the records of a segment of the given length
+    /// following `offset`, with `reader` positioned at `offset`.
+    pub(crate) fn resume(reader: R, segment: u64, offset: u64, len: u64, last: bool) -> Self {
+        RecordReader {
+            reader,
+            segment,
+            offset,
+            len,
+            last,
+            done: false,
+        }
+    }
+
+    fn torn_or_corrupt(&self) -> KvStoreError {
+        if self.last {
+            KvStoreError::TornWrite {
+                segment: self.segment,
+                offset: self.offset,
+            }
+        } else {
+            KvStoreError::CorruptRecord {
+                segment: self.segment,
+                offset: self.offset,
+            }
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Record> {
+        if self.len < SEGMENT_HEADER_LEN || self.len - self.offset < RECORD_HEADER_LEN {
+            return Err(self.torn_or_corrupt());
+        }
+
+        let mut header = [0; RECORD_HEADER_LEN as usize];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })?;
+        let (payload_len, crc) = parse_header(&header);
+
+        let len = RECORD_HEADER_LEN + payload_len;
+        if self.len - self.offset < len {
+            return Err(self.torn_or_corrupt());
+        }
+
+        let mut payload = vec![0; payload_len as usize];
+        self.reader
+            .read_exact(&mut payload)
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })?;
+
+        if checksum(&payload) != crc {
+            // Only the very last record can be the result of a torn write.
+            if self.offset + len == self.len {
+                return Err(self.torn_or_corrupt());
+            }
+
+            return Err(KvStoreError::CorruptRecord {
+                segment: self.segment,
+                offset: self.offset,
+            });
+        }
+
+        let record = Record {
+            offset: self.offset,
+            len,
+            payload,
+        };
+        self.offset += len;
+
+        Ok(record)
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.len {
+            return None;
+        }
+
+        let record = self.read_record();
+        if record.is_err() {
+            self.done = true;
+        }
+
+        Some(record)
+    }
+}
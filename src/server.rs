@@ -1,33 +1,53 @@
 use crate::network::{Req, Resp, SuccResp};
 use log::error;
-use std::fs;
-use std::io::Read;
 use std::io::Write;
 use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum number of watches served at the same time.
+const MAX_WATCHES: usize = 128;
+
+/// Interval at which a watch without writes checks whether its client closed
+/// the connection.
+const WATCH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Represents a database server instance, wrapping a datastore, accepting
 /// incoming connections.
 #[derive(Clone)]
 pub struct Server<E, P>
 where
-    E: crate::KvsEngine + Sync + std::panic::RefUnwindSafe + std::panic::UnwindSafe,
+    E: crate::KvsEngine + Sync,
     P: crate::thread_pool::ThreadPool,
 {
     db: E,
     pool: P,
+    // Number of watches being served.
+    watches: Arc<AtomicUsize>,
 }
 
 impl<E, P> Server<E, P>
 where
-    E: crate::KvsEngine + Sync + std::panic::RefUnwindSafe + std::panic::UnwindSafe,
+    E: crate::KvsEngine + Sync,
     P: crate::thread_pool::ThreadPool,
 {
     /// Construct a new server.
-    pub fn new(db_path: &std::path::Path, threads: u32) -> Server<E, P> {
-        let db = <E>::open(db_path).unwrap();
-        let pool = <P>::new(threads).unwrap();
+    pub fn new(db_path: &std::path::Path, threads: u32) -> Result<Server<E, P>> {
+        Server::with_engine(<E>::open(db_path)?, threads)
+    }
 
-        Server { db, pool }
+    /// Construct a new server serving the given, already opened engine.
+    pub fn with_engine(db: E, threads: u32) -> Result<Server<E, P>> {
+        let pool = <P>::new(threads)?;
+
+        Ok(Server {
+            db,
+            pool,
+            watches: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
     /// Listen on the given address for incoming requests.
@@ -37,40 +57,177 @@ where
         for stream in listener.incoming() {
             let stream = stream?;
             let db = self.db.clone();
-            self.pool.spawn(move || match handle(stream, db) {
+            let watches = self.watches.clone();
+            // A panicking handler only tears down its own connection. Engines
+            // recover from the locks it poisons, but for KvStore failing all
+            // writes from then on, as its log state might be half updated.
+            self.pool.spawn(AssertUnwindSafe(move || match handle(stream, db, watches) {
                 Ok(()) => {}
                 Err(e) => error!("failed to handle stream: {:?}", e),
-            })
+            }))
         }
 
         Ok(())
     }
 }
 
-fn handle<E>(mut stream: TcpStream, db: E) -> Result<()>
+fn handle<E>(mut stream: TcpStream, db: E, watches: Arc<AtomicUsize>) -> Result<()>
 where
-    E: crate::KvsEngine + Sync + std::panic::RefUnwindSafe,
+    E: crate::KvsEngine + Sync,
 {
-    let mut req_stream = serde_json::Deserializer::from_reader(&stream).into_iter::<Req>();
-
-    let req = req_stream
-        .next()
-        .ok_or_else(|| ServerError::ClosedStream)??;
+    let req: Req = bincode::deserialize_from(&stream).map_err(|e| match *e {
+        bincode::ErrorKind::Io(ref c) if c.kind() == std::io::ErrorKind::UnexpectedEof => ServerError::ClosedStream,
+        _ => ServerError::Bincode(e),
+    })?;
 
     let resp: Resp = match req {
-        Req::Get(k) => db.get(k).map(|v| SuccResp::Get(v)),
-        Req::Set(k, v) => db.set(k, v).map(|()| SuccResp::Set),
-        Req::Remove(k) => db.remove(k).map(|()| SuccResp::Remove),
+        Req::Get(k) => db.get_bytes(k).map(SuccResp::Get),
+        Req::Set(k, v) => db.set_bytes(k, v).map(|()| SuccResp::Set),
+        Req::SetWithTtl(k, v, ttl) => db.set_with_ttl_bytes(k, v, ttl).map(|()| SuccResp::Set),
+        Req::Remove(k) => db.remove_bytes(k).map(|()| SuccResp::Remove),
+        Req::ScanPrefix(prefix) => return scan_prefix(stream, db, prefix),
+        Req::Watch(prefix) => return spawn_watch(stream, db, prefix, watches),
+        Req::CompareAndSwap(k, expected, new) => match db.compare_and_swap_bytes(k, expected, new) {
+            Err(crate::KvStoreError::ConditionFailed) => Ok(SuccResp::ConditionFailed),
+            resp => resp.map(|()| SuccResp::CompareAndSwap),
+        },
+        Req::Incr(k, delta) => db.increment_bytes(k, delta).map(SuccResp::Incr),
+        Req::Transaction(batch) => match db.write_batch(batch) {
+            Err(crate::KvStoreError::ConditionFailed) => Ok(SuccResp::ConditionFailed),
+            resp => resp.map(|()| SuccResp::Transaction),
+        },
     }
     .map_err(|e| crate::network::Error::Server(e.to_string()));
 
-    let serialized = serde_json::to_string(&resp)?;
+    let serialized = bincode::serialize(&resp)?;
+
+    stream.write_all(&serialized)?;
+
+    Ok(())
+}
+
+/// Streams the result of a prefix scan, writing each pair as soon as it is
+/// read from the engine.
+fn scan_prefix<E>(stream: TcpStream, db: E, prefix: Vec<u8>) -> Result<()>
+where
+    E: crate::KvsEngine + Sync,
+{
+    let mut writer = std::io::BufWriter::new(stream);
+
+    let resps = match db.scan_prefix_bytes(prefix) {
+        Ok(scan) => scan.map(|pair| pair.map(|(k, v)| SuccResp::Entry(k, v))),
+        Err(e) => {
+            let resp: Resp = Err(crate::network::Error::Server(e.to_string()));
+            bincode::serialize_into(&mut writer, &resp)?;
+            return writer.flush().map_err(ServerError::from);
+        }
+    };
+
+    for resp in resps.chain(std::iter::once(Ok(SuccResp::ScanEnd))) {
+        let resp: Resp = resp.map_err(|e| crate::network::Error::Server(e.to_string()));
+        bincode::serialize_into(&mut writer, &resp)?;
+
+        // The client stops reading at the first error.
+        if resp.is_err() {
+            break;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Serves a watch on a thread of its own, as it lasts as long as the client
+/// keeps the connection open, leaving the pool to other requests. Fails the
+/// watch if there are too many already.
+fn spawn_watch<E>(stream: TcpStream, db: E, prefix: Vec<u8>, watches: Arc<AtomicUsize>) -> Result<()>
+where
+    E: crate::KvsEngine + Sync,
+{
+    if watches.fetch_add(1, Ordering::SeqCst) >= MAX_WATCHES {
+        watches.fetch_sub(1, Ordering::SeqCst);
+        let resp: Resp = Err(crate::network::Error::Server(format!(
+            "too many watches, at most {} are served",
+            MAX_WATCHES
+        )));
+        bincode::serialize_into(&stream, &resp)?;
+        return Ok(());
+    }
 
-    stream.write_all(serialized.as_bytes())?;
+    std::thread::spawn(move || {
+        if let Err(e) = watch(stream, db, prefix) {
+            error!("failed to handle watch: {:?}", e);
+        }
+        watches.fetch_sub(1, Ordering::SeqCst);
+    });
 
     Ok(())
 }
 
+/// Streams the writes to keys starting with the given prefix, until either
+/// the engine ends the watch or the client closes the connection.
+///
+/// Writes are waited for in intervals, checking the connection while there
+/// are none. The watch is dropped along with the connection.
+fn watch<E>(stream: TcpStream, db: E, prefix: Vec<u8>) -> Result<()>
+where
+    E: crate::KvsEngine + Sync,
+{
+    let mut writer = std::io::BufWriter::new(stream);
+
+    let mut events = match db.watch(prefix) {
+        Ok(events) => events,
+        Err(e) => {
+            let resp: Resp = Err(crate::network::Error::Server(e.to_string()));
+            bincode::serialize_into(&mut writer, &resp)?;
+            return writer.flush().map_err(ServerError::from);
+        }
+    };
+
+    loop {
+        let event = match events.recv_timeout(WATCH_CHECK_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if is_closed(writer.get_ref()) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let resp: Resp = event
+            .map(SuccResp::Event)
+            .map_err(|e| crate::network::Error::Server(e.to_string()));
+
+        // The client going away is the regular end of a watch.
+        if bincode::serialize_into(&mut writer, &resp).is_err() || writer.flush().is_err() {
+            return Ok(());
+        }
+
+        // The client stops reading at the first error.
+        if resp.is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether the client closed the connection, without waiting for it
+/// to send anything. Clients do not send anything past their request.
+fn is_closed(stream: &TcpStream) -> bool {
+    let mut buf = [0; 1];
+    let peeked = stream
+        .set_nonblocking(true)
+        .and_then(|()| stream.peek(&mut buf));
+    let blocking = stream.set_nonblocking(false);
+
+    match peeked {
+        Ok(0) => true,
+        Ok(_) => blocking.is_err(),
+        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => blocking.is_err(),
+        Err(_) => true,
+    }
+}
+
 type Result<T> = std::result::Result<T, ServerError>;
 
 /// Error type for KvsServer.
@@ -82,8 +239,8 @@ pub enum ServerError {
     ClosedStream,
     /// Io error wrapper.
     Io(std::io::Error),
-    /// Serde json error wrapper.
-    SerdeJson(serde_json::error::Error),
+    /// Bincode error wrapper.
+    Bincode(bincode::Error),
 }
 
 impl From<crate::KvStoreError> for ServerError {
@@ -98,8 +255,8 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
-impl From<serde_json::error::Error> for ServerError {
-    fn from(err: serde_json::error::Error) -> ServerError {
-        ServerError::SerdeJson(err)
+impl From<bincode::Error> for ServerError {
+    fn from(err: bincode::Error) -> ServerError {
+        ServerError::Bincode(err)
     }
 }
@@ -0,0 +1,377 @@
+use crate::batch::{BatchOp, WriteBatch};
+use crate::error::{KvStoreError, Result};
+use crate::store::{expiry_millis, now_millis};
+use crate::watch::{WatchEvent, Watchers};
+use crate::{KeysBytes, KvsEngine, ScanBytes, Watch};
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Name of the tree holding the batch currently being applied.
+const BATCH_TREE: &str = "kvs-batch";
+
+/// Key of the batch currently being applied within `BATCH_TREE`.
+const BATCH_KEY: &str = "pending";
+
+/// Name of the tree holding the expiry of keys set with a TTL.
+const EXPIRY_TREE: &str = "kvs-expiry";
+
+/// SledKvsEngine stores values by their key using the sled embedded database.
+///
+/// # Example
+///
+/// ``` rust
+/// use kvs::{KvsEngine, SledKvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = SledKvsEngine::open(temp_dir.path()).unwrap();
+///
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+///
+/// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+    // sled does not offer atomic batches itself. Instead a batch is first
+    // persisted as a whole in a separate tree, applied, and only then removed.
+    // A batch left behind by a crash is applied again on open.
+    batch: Arc<sled::Tree>,
+    // Held by all writes, as a value and its expiry are updated separately,
+    // there is room for a single pending batch and the expectations of a
+    // batch must hold until applied.
+    write_lock: Arc<Mutex<()>>,
+    // Expiry in big endian milliseconds since the unix epoch by key. Expired
+    // values are hidden from reads and left in place until overwritten, as
+    // deleting them would race with concurrent writes.
+    expiry: Arc<sled::Tree>,
+    // Whether every write is flushed to disk before it is acknowledged.
+    sync: bool,
+    // Notified of every write under the write lock, thus in commit order.
+    watchers: Arc<Watchers>,
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn open(path: &std::path::Path) -> Result<Self> {
+        SledKvsEngine::open_with(path, false)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.db.set(&key, value.as_slice())?;
+        self.expiry.del(&key)?;
+        self.flush_if_sync()?;
+
+        self.notify_set(&key, &value);
+
+        Ok(())
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let expires = expiry_millis(ttl)?;
+        // Written ahead of the value, so a crash in between at most expires
+        // the previous value early.
+        self.expiry.set(&key, expires.to_be_bytes().to_vec())?;
+        self.db.set(&key, value.as_slice())?;
+        self.flush_if_sync()?;
+
+        self.notify_set(&key, &value);
+
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if is_expired(&self.expiry, &key)? {
+            return Ok(None);
+        }
+
+        Ok(self.db.get(&key)?.map(|v| v.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let expired = is_expired(&self.expiry, &key)?;
+        if self.db.del(&key)?.is_none() {
+            return Err(KvStoreError::KeyNotFound);
+        }
+        self.expiry.del(&key)?;
+        self.flush_if_sync()?;
+
+        self.notify_remove(&key);
+        if expired {
+            return Err(KvStoreError::KeyNotFound);
+        }
+
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for (k, expected) in &batch.expected {
+            if self.get_bytes(k.clone())? != *expected {
+                return Err(KvStoreError::ConditionFailed);
+            }
+        }
+
+        // Whether a key set or removed earlier within the batch is live.
+        let mut live: HashMap<&[u8], bool> = HashMap::new();
+        for op in &batch.ops {
+            match op {
+                BatchOp::Set(k, _) => {
+                    live.insert(k, true);
+                }
+                BatchOp::Remove(k) => {
+                    let exists = match live.get(k.as_slice()) {
+                        Some(live) => *live,
+                        None => self.get_bytes(k.clone())?.is_some(),
+                    };
+                    if !exists {
+                        return Err(KvStoreError::KeyNotFound);
+                    }
+                    live.insert(k, false);
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let pending =
+            bincode::serialize(&batch).map_err(|c| KvStoreError::BinarySerializationFailure { c })?;
+        // sled recovers a prefix of its writes after a crash, so the batch is
+        // recovered whenever any of its writes is.
+        self.batch.set(BATCH_KEY, pending)?;
+
+        self.apply_batch(&batch)
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        // An expired value counts as absent, but is still what gets swapped.
+        let expired = is_expired(&self.expiry, &key)?;
+        let old = if expired {
+            if expected.is_some() {
+                return Err(KvStoreError::ConditionFailed);
+            }
+            self.db.get(&key)?.map(|v| v.to_vec())
+        } else {
+            expected
+        };
+
+        // A value replacing a live one keeps its expiry.
+        let keep_expiry = !expired && old.is_some() && new.is_some();
+        self.db
+            .cas(&key, old.as_ref(), new.as_deref())?
+            .map_err(|_| KvStoreError::ConditionFailed)?;
+        if !keep_expiry {
+            self.expiry.del(&key)?;
+        }
+        self.flush_if_sync()?;
+
+        match new {
+            Some(value) => self.notify_set(&key, &value),
+            // Swapping an absent key for none writes nothing.
+            None if old.is_some() => self.notify_remove(&key),
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn scan_bytes(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>, limit: Option<usize>) -> Result<ScanBytes> {
+        let pairs = Cursor::new(self.db.clone(), self.expiry.clone(), start, end)
+            .map(|pair| pair.map(|(k, v)| (k, v.to_vec())));
+
+        match limit {
+            Some(limit) => Ok(Box::new(pairs.take(limit))),
+            None => Ok(Box::new(pairs)),
+        }
+    }
+
+    fn keys_bytes(&self) -> Result<KeysBytes> {
+        Ok(Box::new(
+            Cursor::new(self.db.clone(), self.expiry.clone(), None, None).map(|pair| pair.map(|(k, _)| k)),
+        ))
+    }
+
+    /// Watch all writes to keys starting with `prefix`, committed from now
+    /// on. sled neither assigns sequence numbers to writes nor records their
+    /// time, nor are keys reported once they expire.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watch> {
+        Ok(self.watchers.watch(prefix))
+    }
+}
+
+impl SledKvsEngine {
+    /// Opens the database at the given path.
+    ///
+    /// With `sync` set, every write is flushed to disk before it is
+    /// acknowledged. Otherwise sled flushes writes in the background, so the
+    /// latest writes are lost if the process crashes.
+    pub fn open_with(path: &std::path::Path, sync: bool) -> Result<SledKvsEngine> {
+        crate::manifest::claim_dir(path, "sled")?;
+
+        let db = sled::Db::start_default(path)?;
+        let batch = db.open_tree(BATCH_TREE)?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+
+        let engine = SledKvsEngine {
+            db,
+            batch,
+            write_lock: Arc::new(Mutex::new(())),
+            expiry,
+            sync,
+            watchers: Arc::new(Watchers::default()),
+        };
+
+        if let Some(pending) = engine.batch.get(BATCH_KEY)? {
+            let batch: WriteBatch = bincode::deserialize(&pending)
+                .map_err(|c| KvStoreError::BinaryDeserializationFailure { c })?;
+            engine.apply_batch(&batch)?;
+        }
+
+        Ok(engine)
+    }
+
+    /// Flushes all writes so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn flush_if_sync(&self) -> Result<()> {
+        if self.sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn notify_set(&self, key: &[u8], value: &[u8]) {
+        self.watchers.notify(key, || WatchEvent::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            seq: None,
+            time: None,
+        });
+    }
+
+    fn notify_remove(&self, key: &[u8]) {
+        self.watchers.notify(key, || WatchEvent::Remove {
+            key: key.to_vec(),
+            seq: None,
+            time: None,
+        });
+    }
+
+    /// Applies the writes of the persisted pending batch, then removes it.
+    /// Applying a batch more than once has no further effect.
+    fn apply_batch(&self, batch: &WriteBatch) -> Result<()> {
+        for op in &batch.ops {
+            match op {
+                BatchOp::Set(k, v) => {
+                    self.db.set(k, v.clone())?;
+                    self.expiry.del(k)?;
+                }
+                BatchOp::Remove(k) => {
+                    self.db.del(k)?;
+                    self.expiry.del(k)?;
+                }
+            }
+        }
+
+        self.batch.del(BATCH_KEY)?;
+        self.flush_if_sync()?;
+
+        for op in &batch.ops {
+            match op {
+                BatchOp::Set(k, v) => self.notify_set(k, v),
+                BatchOp::Remove(k) => self.notify_remove(k),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns whether the value of the given key expired.
+fn is_expired(expiry: &sled::Tree, key: &[u8]) -> Result<bool> {
+    let expires = match expiry.get(key)? {
+        Some(expires) => expires,
+        None => return Ok(false),
+    };
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&expires);
+
+    Ok(u64::from_be_bytes(bytes) <= now_millis())
+}
+
+/// Cursor walks the keys of the database in ascending order, looking up the
+/// key following the previous one on every step and skipping expired keys.
+struct Cursor {
+    db: sled::Db,
+    expiry: Arc<sled::Tree>,
+    next: Bound<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl Cursor {
+    fn new(db: sled::Db, expiry: Arc<sled::Tree>, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> Cursor {
+        let next = match start {
+            Some(start) => Bound::Included(start),
+            None => Bound::Unbounded,
+        };
+
+        Cursor {
+            db,
+            expiry,
+            next,
+            end,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Result<(Vec<u8>, sled::IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let pair = match self.db.range((self.next.clone(), Bound::Unbounded)).next()? {
+            Ok(pair) => pair,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        };
+
+        if let Some(end) = &self.end {
+            if pair.0 >= *end {
+                self.done = true;
+                return None;
+            }
+        }
+
+        self.next = Bound::Excluded(pair.0.clone());
+
+        match is_expired(&self.expiry, &pair.0) {
+            Ok(true) => self.next(),
+            Ok(false) => Some(Ok(pair)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -1,10 +1,268 @@
 use crate::error::{Result, KvStoreError};
-use crate::KvsEngine;
+use crate::batch::{BatchOp, WriteBatch};
+use crate::hint::{self, Hint, HintEntry, HintKind};
+use crate::record::{self, RecordReader};
+use crate::transaction::Transaction;
+use crate::watch::{WatchEvent, Watchers};
+use crate::{utf8, KeysBytes, KvsEngine, Scan, ScanBytes, Watch};
+use crossbeam_skiplist::SkipMap;
+use failure::Fail;
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use std::io::Seek;
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::io::{Seek, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default size in bytes after which a log segment is sealed.
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Default amount of stale bytes needed before compaction is considered at
+/// all.
+const DEFAULT_COMPACTION_MIN_BYTES: u64 = 1024 * 1024;
+
+/// Default amount of data a single compaction run reads at most.
+const DEFAULT_COMPACTION_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Interval at which tombstones are written for expired keys.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the single log file used by earlier versions of KvStore.
+const LEGACY_LOG_FILE: &str = "db";
+
+/// Options to tune a KvStore, passed to `KvStore::open_with` and
+/// `KvStore::open_read_only_with`.
+///
+/// # Example
+///
+/// ``` rust
+/// use kvs::{KvStore, KvStoreOptions};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let options = KvStoreOptions::new().max_segment_size(1024);
+/// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+///
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    max_segment_size: u64,
+    sync_policy: SyncPolicy,
+    compaction_ratio: f64,
+    compaction_min_bytes: u64,
+    compaction_max_bytes: u64,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    create_if_missing: bool,
+    error_if_exists: bool,
+    history_retention: u64,
+    follow_interval: Option<Duration>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            sync_policy: SyncPolicy::Never,
+            compaction_ratio: 1.0,
+            compaction_min_bytes: DEFAULT_COMPACTION_MIN_BYTES,
+            compaction_max_bytes: DEFAULT_COMPACTION_MAX_BYTES,
+            max_key_size: None,
+            max_value_size: None,
+            create_if_missing: true,
+            error_if_exists: false,
+            history_retention: 0,
+            follow_interval: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Create options with default values.
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Set the size in bytes after which the active log segment is sealed and
+    /// writes move on to a new segment. A single record larger than this still
+    /// ends up in one segment.
+    pub fn max_segment_size(mut self, size: u64) -> Self {
+        self.max_segment_size = size;
+        self
+    }
+
+    /// Set when writes are synced to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Set how many bytes of stale records there need to be for every byte of
+    /// live records before the log is compacted. Defaults to 1.0, compacting
+    /// once the log is more than twice the size of its live records.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Set the amount of stale bytes below which the log is never compacted,
+    /// regardless of the compaction ratio. Defaults to 1 MiB.
+    pub fn compaction_min_bytes(mut self, bytes: u64) -> Self {
+        self.compaction_min_bytes = bytes;
+        self
+    }
+
+    /// Set the amount of data a single compaction run reads at most. Each run
+    /// compacts the segments with the most stale bytes first, and at least
+    /// one segment worth compacting. Defaults to 64 MiB.
+    pub fn compaction_max_bytes(mut self, bytes: u64) -> Self {
+        self.compaction_max_bytes = bytes;
+        self
+    }
+
+    /// Set the size in bytes of the largest key accepted by writes, failing
+    /// larger ones with `KvStoreError::KeyTooLarge`. Defaults to no limit.
+    pub fn max_key_size(mut self, size: usize) -> Self {
+        self.max_key_size = Some(size);
+        self
+    }
+
+    /// Set the size in bytes of the largest value accepted by writes, failing
+    /// larger ones with `KvStoreError::ValueTooLarge`. Defaults to no limit.
+    pub fn max_value_size(mut self, size: usize) -> Self {
+        self.max_value_size = Some(size);
+        self
+    }
+
+    /// Set whether opening a directory without a store creates one, including
+    /// the directory itself. Fails with `KvStoreError::StoreNotFound`
+    /// otherwise. Defaults to true. Ignored by read-only stores.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Set whether opening a directory already holding a store fails with
+    /// `KvStoreError::StoreExists`. Defaults to false. Ignored by read-only
+    /// stores.
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.error_if_exists = error;
+        self
+    }
+
+    /// Set the number of most recent writes whose superseded versions are
+    /// kept, as returned by `KvStore::history` and `KvStore::get_at`.
+    /// Compaction carries these versions over. Defaults to 0, keeping no
+    /// history.
+    pub fn history_retention(mut self, writes: u64) -> Self {
+        self.history_retention = writes;
+        self
+    }
+
+    /// Set the interval at which a store opened read-only catches up with the
+    /// writes of the store writing to the same directory, as done by
+    /// `KvStore::refresh`. Defaults to never. Ignored by writable stores.
+    pub fn follow(mut self, interval: Duration) -> Self {
+        self.follow_interval = Some(interval);
+        self
+    }
+
+    /// Checks that the options make sense, failing with
+    /// `KvStoreError::InvalidOptions` otherwise.
+    fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| {
+            Err(KvStoreError::InvalidOptions {
+                reason: reason.to_owned(),
+            })
+        };
+
+        if self.max_segment_size == 0 {
+            return invalid("max segment size must be positive");
+        }
+        if !self.compaction_ratio.is_finite() || self.compaction_ratio < 0.0 {
+            return invalid("compaction ratio must be a non-negative number");
+        }
+        if self.compaction_max_bytes == 0 {
+            return invalid("compaction max bytes must be positive");
+        }
+        if self.max_key_size == Some(0) {
+            return invalid("max key size must be positive");
+        }
+        if self.max_value_size == Some(0) {
+            return invalid("max value size must be positive");
+        }
+        if self.sync_policy == SyncPolicy::Interval(Duration::from_secs(0)) {
+            return invalid("sync interval must be positive");
+        }
+        if self.error_if_exists && !self.create_if_missing {
+            return invalid("error if exists requires create if missing");
+        }
+        if self.follow_interval == Some(Duration::from_secs(0)) {
+            return invalid("follow interval must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// SyncPolicy decides when writes are synced to disk.
+///
+/// A write that is not synced yet survives the process crashing, but is lost
+/// when the machine crashes or loses power.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave syncing to the operating system. Sealed segments are still
+    /// synced.
+    Never,
+    /// Sync every write before acknowledging it.
+    Always,
+    /// Sync at most the given duration after a write.
+    Interval(Duration),
+    /// Sync once the given amount of bytes was written since the last sync.
+    Bytes(u64),
+}
+
+/// Options for a single write, passed to `KvStore::set_with` and
+/// `KvStore::remove_with`.
+///
+/// # Example
+///
+/// ``` rust
+/// use kvs::{KvStore, WriteOptions};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+///
+/// let options = WriteOptions::new().sync(true);
+/// store.set_with("key1".to_owned(), "value1".to_owned(), &options).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    sync: bool,
+}
 
+impl WriteOptions {
+    /// Create options with default values.
+    pub fn new() -> Self {
+        WriteOptions::default()
+    }
+
+    /// Sync the write to disk before acknowledging it, regardless of the
+    /// sync policy of the store.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
 
 /// KvStore stores values by their key.
 ///
@@ -19,299 +277,3319 @@ use std::sync::{Arc, Mutex};
 ///
 /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
 /// store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+/// store.set_bytes(vec![0, 255], vec![159, 146, 150]).unwrap();
 ///
 /// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
 /// assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+/// assert_eq!(store.get_bytes(vec![0, 255]).unwrap(), Some(vec![159, 146, 150]));
 /// ```
 ///
 #[derive(Clone)]
 pub struct KvStore {
-    indexed_log_file: Arc<Mutex<IndexedLogFile>>,
-    // Needed later for compaction when replacing the old version by the
-    // compacted one.
-    path: std::path::PathBuf,
+    indexed_log: Arc<IndexedLog>,
+    // Read-only stores never compact.
+    compactor: Option<Arc<Compactor>>,
+    // Only read-only stores follow a writer. Dropped along with the last
+    // handle to the store, stopping its thread.
+    _follower: Option<Arc<Follower>>,
 }
 
 impl KvsEngine for KvStore {
     fn open(path: &std::path::Path) -> Result<Self> {
         KvStore::open(path)
     }
-    fn set(&self, key: String, value: String) -> Result<()> {
-        KvStore::set(self, key, value)
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_bytes(key, value)
     }
-    fn get(&self, key: String) -> Result<Option<String>>{
-        self.get(key)
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.get_bytes(key)
     }
-    fn remove(&self, key: String) -> Result<()> {
-        self.remove(key)
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.remove_bytes(key)
+    }
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch)
+    }
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.compare_and_swap_bytes(key, expected, new)
+    }
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key, value, ttl)
+    }
+    fn scan_bytes(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>, limit: Option<usize>) -> Result<ScanBytes> {
+        self.scan_bytes(start, end, limit)
+    }
+    fn keys_bytes(&self) -> Result<KeysBytes> {
+        self.keys_bytes()
+    }
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watch> {
+        self.watch(prefix)
     }
 }
 
 impl KvStore {
     /// Create new KvStore from file.
     pub fn open(path: &std::path::Path) -> Result<KvStore> {
-        let log_file = IndexedLogFile::new(path)?;
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Create new KvStore from file with the given options.
+    ///
+    /// The directory is locked for as long as the store is open, failing with
+    /// `KvStoreError::DirectoryLocked` if another store has it open already.
+    pub fn open_with(path: &std::path::Path, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+
+        if store_exists(path)? {
+            if options.error_if_exists {
+                return Err(KvStoreError::StoreExists {
+                    name: path.display().to_string(),
+                });
+            }
+        } else if options.create_if_missing {
+            std::fs::create_dir_all(path).map_err(|c| KvStoreError::CreateDirFailure {
+                c,
+                name: path.display().to_string(),
+            })?;
+        } else {
+            return Err(KvStoreError::StoreNotFound {
+                name: path.display().to_string(),
+            });
+        }
+
+        crate::manifest::claim_dir(path, "kvs")?;
+
+        let indexed_log = Arc::new(IndexedLog::open(path, options, false)?);
 
         let kvs = KvStore {
-            indexed_log_file: Arc::new(Mutex::new(log_file)),
-            path: path.to_path_buf(),
+            compactor: Some(Arc::new(Compactor::spawn(indexed_log.clone()))),
+            _follower: None,
+            indexed_log,
         };
 
         Ok(kvs)
     }
 
-    /// Returns the value for the given key.
+    /// Opens the store in the given directory for reading only, as of now.
+    ///
+    /// A read-only store never modifies the directory, nor does it lock it,
+    /// thus it can be opened alongside a store writing to the same directory.
+    /// All writes fail with `KvStoreError::ReadOnly`. Writes of the writer
+    /// become visible through `refresh`.
+    ///
+    /// # Example
+    ///
+    /// ``` rust
+    /// use kvs::{KvStore, KvStoreError};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    ///
+    /// let reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+    /// assert_eq!(reader.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    /// match reader.set("key1".to_owned(), "value2".to_owned()) {
+    ///     Err(KvStoreError::ReadOnly) => {}
+    ///     _ => panic!("expected read-only error"),
+    /// }
+    /// ```
+    pub fn open_read_only(path: &std::path::Path) -> Result<KvStore> {
+        KvStore::open_read_only_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store in the given directory for reading only with the given
+    /// options, following the writes of the writer if asked to.
+    pub fn open_read_only_with(path: &std::path::Path, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+        crate::manifest::check_dir(path, "kvs")?;
+
+        let follow_interval = options.follow_interval;
+        let indexed_log = Arc::new(IndexedLog::open(path, options, true)?);
+
+        Ok(KvStore {
+            _follower: follow_interval.map(|interval| Arc::new(Follower::spawn(indexed_log.clone(), interval))),
+            compactor: None,
+            indexed_log,
+        })
+    }
+
+    /// Returns the value for the given key, failing on a value that is not
+    /// valid utf8.
     pub fn get(&self, k: String) -> Result<Option<String>> {
-        let cmd = self.indexed_log_file.lock().unwrap().read(k);
+        self.get_bytes(k.into_bytes())?.map(utf8).transpose()
+    }
 
-        // Don't error when key is not found.
-        if let Err(KvStoreError::KeyNotFound) = cmd {
-            return Ok(None);
-        }
+    /// Returns the value for the given key.
+    pub fn get_bytes(&self, k: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let cmd = self.indexed_log.read(&k)?;
 
-        if let Some(cmd) =  cmd? {
-            Ok(cmd.value())
-        } else {
-            Ok(None)
-        }
+        Ok(cmd.and_then(Command::into_value))
     }
 
     /// Sets the value for the given key.
     pub fn set(&self, k: String, v: String) -> Result<()> {
-        self.indexed_log_file.lock().unwrap().write(Command::Set{k: k.clone(),v})?;
+        self.set_bytes(k.into_bytes(), v.into_bytes())
+    }
 
-        if self.should_compact() {
-            return self.compact_log();
-        }
+    /// Sets the value for the given key.
+    pub fn set_bytes(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()> {
+        self.set_with(k, v, &WriteOptions::default())
+    }
+
+    /// Sets the value for the given key with the given write options.
+    pub fn set_with(&self, k: impl Into<Vec<u8>>, v: impl Into<Vec<u8>>, options: &WriteOptions) -> Result<()> {
+        let cmd = Command::Set {
+            k: k.into(),
+            v: v.into(),
+            e: None,
+        };
+        self.indexed_log.write(vec![cmd], None, options)?;
+
+        self.trigger_compaction();
+
+        Ok(())
+    }
+
+    /// Sets the value for the given key, expiring after the given duration.
+    pub fn set_with_ttl(&self, k: String, v: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(k.into_bytes(), v.into_bytes(), ttl)
+    }
+
+    /// Sets the value for the given key, expiring after the given duration.
+    ///
+    /// An expired key reads as absent right away. Its value is removed from
+    /// the log in the background.
+    pub fn set_with_ttl_bytes(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()> {
+        let e = Some(expiry_millis(ttl)?);
+        self.indexed_log.write(vec![Command::Set { k, v, e }], None, &WriteOptions::default())?;
+
+        self.trigger_compaction();
 
         Ok(())
     }
 
     /// Removes the value of the given key.
     pub fn remove(&self, k: String) -> Result<()> {
-        let exists = self.indexed_log_file.lock().unwrap().read(k.clone())?;
-        if exists.is_none() {
-            return Err(KvStoreError::KeyNotFound);
-        }
+        self.remove_bytes(k.into_bytes())
+    }
 
-        self.indexed_log_file.lock().unwrap().write(Command::Remove { k: k.clone() })
+    /// Removes the value of the given key.
+    pub fn remove_bytes(&self, k: Vec<u8>) -> Result<()> {
+        self.remove_with(k, &WriteOptions::default())
     }
 
-    fn should_compact(&self) -> bool {
-        let index_size = self.indexed_log_file.lock().unwrap().index.len();
+    /// Removes the value of the given key with the given write options.
+    pub fn remove_with(&self, k: impl Into<Vec<u8>>, options: &WriteOptions) -> Result<()> {
+        self.indexed_log.write(vec![Command::Remove { k: k.into() }], None, options)?;
 
-        let num_writes = self.indexed_log_file.lock().unwrap().log_file.num_writes;
+        self.trigger_compaction();
 
-        num_writes > 2 * index_size
+        Ok(())
     }
 
-    fn compact_log(&self) -> Result<()> {
-        // TODO: No reason to clone this thing except borrow checker.
-        let old_index = self.indexed_log_file.lock().unwrap().index.clone();
+    /// Applies all writes of the given batch atomically.
+    ///
+    /// The batch is appended to the log as a whole. After a crash either all
+    /// or none of its writes are recovered.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_with(batch, &WriteOptions::default())
+    }
 
-        let tmp_folder = tempfile::tempdir()
-            .map_err(|c| KvStoreError::OpenTmpDirFailure { c })?;
+    /// Applies all writes of the given batch atomically with the given write
+    /// options.
+    pub fn write_batch_with(&self, batch: WriteBatch, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() && batch.expected.is_empty() {
+            return Ok(());
+        }
 
-        let mut tmp_indexed_log = IndexedLogFile::new(tmp_folder.path())?;
+        // A batch without writes still checks its expectations, without
+        // appending anything to the log.
+        let condition = if batch.expected.is_empty() {
+            None
+        } else {
+            Some(Condition::Values(batch.expected))
+        };
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(k, v) => Command::Set { k, v, e: None },
+                BatchOp::Remove(k) => Command::Remove { k },
+            })
+            .collect();
+        self.indexed_log.write(cmds, condition, options)?;
+
+        self.trigger_compaction();
 
-        for (k, _offset) in old_index.iter() {
-            let cmd = self.indexed_log_file.lock().unwrap().read(k.to_string())?;
+        Ok(())
+    }
 
-            let cmd = cmd.ok_or_else(|| KvStoreError::KeyNotFound)?;
+    /// Sets the given key to `new`, or removes it if `new` is `None`, but only
+    /// if it currently holds `expected`. See `compare_and_swap_bytes`.
+    pub fn compare_and_swap(&self, k: String, expected: Option<String>, new: Option<String>) -> Result<()> {
+        self.compare_and_swap_bytes(
+            k.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
 
-            tmp_indexed_log.write(cmd)?;
-        }
+    /// Sets the given key to `new`, or removes it if `new` is `None`, but only
+    /// if it currently holds `expected`. An `expected` value of `None` stands
+    /// for an absent key.
+    ///
+    /// Fails with `KvStoreError::ConditionFailed` if the key holds a
+    /// different value.
+    ///
+    /// A value replacing another one keeps its expiry, if any, whereas a
+    /// value set on an absent key never expires.
+    pub fn compare_and_swap_bytes(&self, k: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let cmd = match new {
+            Some(v) => Command::Set { k: k.clone(), v, e: None },
+            // Nothing to write, the key is to stay absent.
+            None if expected.is_none() => {
+                return match self.get_bytes(k)? {
+                    Some(_) => Err(KvStoreError::ConditionFailed),
+                    None => Ok(()),
+                };
+            }
+            None => Command::Remove { k: k.clone() },
+        };
 
-        std::fs::rename(tmp_folder.path().join("db"), self.path.join("db"))
-            .map_err(|c| KvStoreError::FileMoveFailure{
-                c,
-            })?;
+        let condition = Condition::Swap { key: k, expected };
+        self.indexed_log.write(vec![cmd], Some(condition), &WriteOptions::default())?;
 
-        // TODO: This rebuilds the index again? We still have it in
-        // tmp_indexed_log.index.
-        *self.indexed_log_file.lock().unwrap() = IndexedLogFile::new(&self.path)?;
+        self.trigger_compaction();
 
         Ok(())
     }
-}
 
-type Offset = u64;
+    /// Iterates over at most `limit` key value pairs in ascending key order,
+    /// starting at key `start` and stopping before key `end`, failing on keys
+    /// or values that are not valid utf8.
+    pub fn scan(&self, start: Option<String>, end: Option<String>, limit: Option<usize>) -> Result<Scan> {
+        let pairs = self.scan_bytes(start.map(String::into_bytes), end.map(String::into_bytes), limit)?;
 
-struct IndexedLogFile {
-    log_file: LogFile,
-    index: std::collections::HashMap<String, Offset>,
-}
+        Ok(Box::new(pairs.map(|pair| {
+            let (k, v) = pair?;
+            Ok((utf8(k)?, utf8(v)?))
+        })))
+    }
 
-impl IndexedLogFile {
-    fn new(path: &std::path::Path) -> Result<Self> {
-        let log_file = LogFile::new(path)?;
+    /// Iterates over at most `limit` key value pairs in ascending key order,
+    /// starting at key `start` and stopping before key `end`.
+    ///
+    /// Values are read from the log as the iterator advances.
+    pub fn scan_bytes(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>, limit: Option<usize>) -> Result<ScanBytes> {
+        let pairs = self.read_pairs(Cursor::new(self.indexed_log.clone(), start, end));
 
-        let index = std::collections::HashMap::new();
+        match limit {
+            Some(limit) => Ok(Box::new(pairs.take(limit))),
+            None => Ok(Box::new(pairs)),
+        }
+    }
 
-        let mut indexed_log_file = IndexedLogFile{
-            log_file,
-            index,
-        };
+    /// Reads the value of each key as the returned iterator advances, skipping
+    /// keys removed in the meantime.
+    fn read_pairs<I>(&self, keys: I) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send
+    where
+        I: Iterator<Item = Vec<u8>> + Send,
+    {
+        let indexed_log = self.indexed_log.clone();
+
+        keys.filter_map(move |k| match indexed_log.read(&k) {
+            Ok(cmd) => cmd.and_then(Command::into_value).map(|v| Ok((k, v))),
+            Err(e) => Some(Err(e)),
+        })
+    }
 
-        indexed_log_file.build_index()?;
+    /// Iterates over all keys in ascending order.
+    pub fn keys_bytes(&self) -> Result<KeysBytes> {
+        Ok(Box::new(Cursor::new(self.indexed_log.clone(), None, None).map(Ok)))
+    }
 
-        Ok(indexed_log_file)
+    /// Runs `f` as an optimistic transaction, retrying it until it commits.
+    ///
+    /// Reads within the transaction see the store as of its start, plus the
+    /// transaction's own writes. Those writes are buffered and committed
+    /// atomically, but only if none of the keys read changed in the meantime.
+    /// Otherwise `f` is run again on a fresh transaction. An error returned
+    /// by `f` aborts the transaction.
+    ///
+    /// # Example
+    ///
+    /// ``` rust
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("from".to_owned(), "10".to_owned()).unwrap();
+    ///
+    /// store
+    ///     .transaction(|tx| {
+    ///         let from: i64 = tx.get("from".to_owned())?.unwrap().parse().unwrap();
+    ///         tx.set("from".to_owned(), (from - 3).to_string());
+    ///         tx.set("to".to_owned(), "3".to_owned());
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert_eq!(store.get("from".to_owned()).unwrap(), Some("7".to_owned()));
+    /// ```
+    pub fn transaction<F, T>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        loop {
+            let mut tx = Transaction::new(self.snapshot());
+            let result = f(&mut tx)?;
+
+            match self.write_batch(tx.into_batch()) {
+                Ok(()) => return Ok(result),
+                // Lost against a concurrent write, retry based on its values.
+                Err(KvStoreError::ConditionFailed) => continue,
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    fn read(&mut self, key: String) -> Result<Option<Command>> {
-        let offset = self.index.get(&key)
-            .ok_or_else(|| KvStoreError::KeyNotFound)?;
+    /// Watches all writes to keys starting with `prefix`, committed from now
+    /// on, including the removal of expired keys.
+    ///
+    /// # Example
+    ///
+    /// ``` rust
+    /// use kvs::{KvStore, WatchEvent};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// let mut watch = store.watch(b"key".to_vec()).unwrap();
+    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    ///
+    /// match watch.next().unwrap().unwrap() {
+    ///     WatchEvent::Set { key, value, .. } => assert_eq!((key, value), (b"key1".to_vec(), b"value1".to_vec())),
+    ///     WatchEvent::Remove { .. } => unreachable!(),
+    /// }
+    /// ```
+    pub fn watch(&self, prefix: Vec<u8>) -> Result<Watch> {
+        Ok(self.indexed_log.watchers.watch(prefix))
+    }
 
+    /// Returns a read-only view of the store as of now, unaffected by later
+    /// writes.
+    ///
+    /// Versions superseded by later writes are kept until the snapshot is
+    /// dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            seq: self.indexed_log.open_snapshot(),
+            indexed_log: self.indexed_log.clone(),
+        }
+    }
 
-        self.log_file.read_cmd(*offset as Offset)
+    /// Returns the versions of the given key within the retained history,
+    /// oldest first and ending with the current one. A removal shows up as a
+    /// version without a value.
+    ///
+    /// # Example
+    ///
+    /// ``` rust
+    /// use kvs::{KvStore, KvStoreOptions};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let options = KvStoreOptions::new().history_retention(100);
+    /// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+    ///
+    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    ///
+    /// let history = store.history("key1").unwrap();
+    /// assert_eq!(history[0].value, Some(b"value1".to_vec()));
+    /// assert_eq!(history[1].value, Some(b"value2".to_vec()));
+    /// assert_eq!(store.get_at("key1", history[0].seq).unwrap(), Some(b"value1".to_vec()));
+    /// ```
+    pub fn history(&self, k: impl Into<Vec<u8>>) -> Result<Vec<Version>> {
+        self.indexed_log.history(&k.into())
     }
 
-    fn write(&mut self, cmd: Command) -> Result<()> {
-        let key = cmd.key();
+    /// Returns the value the given key held right after the write with the
+    /// given sequence number. Fails with `KvStoreError::HistoryUnavailable`
+    /// if the write is older than the retained history.
+    pub fn get_at(&self, k: impl Into<Vec<u8>>, seq: u64) -> Result<Option<Vec<u8>>> {
+        let cmd = self.indexed_log.read_at(&k.into(), Some(seq))?;
 
-        let offset = self.log_file.write_cmd(cmd)?;
-        self.index.insert(key, offset);
+        // Checked after reading, as history might be pruned in between.
+        if seq < self.indexed_log.history_horizon() {
+            return Err(KvStoreError::HistoryUnavailable { seq });
+        }
 
-        Ok(())
+        Ok(cmd.and_then(Command::into_value))
     }
 
-    fn build_index(&mut self) -> Result<()>  {
-        let mut log_size = 0;
-        let mut offset: Offset = 0;
+    /// Returns the sequence number of the last committed write, 0 for an
+    /// empty store.
+    ///
+    /// Every write gets the next sequence number, shared by all commands of a
+    /// batch, which is stored in the log along with the time of the write.
+    pub fn last_sequence(&self) -> u64 {
+        self.indexed_log.read_writer().seq
+    }
 
-        let reader = self.log_file.get_reader(offset)?;
+    /// Syncs all writes so far to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.indexed_log.sync()
+    }
 
-        let mut stream = serde_json::Deserializer::from_reader(reader)
-            .into_iter::<Command>();
+    /// Compacts the log, returning once compaction is done.
+    ///
+    /// Compaction usually runs in the background whenever the log contains
+    /// enough stale data. This forces a compaction run, e.g. to reclaim disk
+    /// space right away. A run compacts the segments with the most stale data,
+    /// up to `KvStoreOptions::compaction_max_bytes`.
+    pub fn compact(&self) -> Result<()> {
+        match &self.compactor {
+            Some(compactor) => compactor.compact(),
+            None => Err(KvStoreError::ReadOnly),
+        }
+    }
 
-        while let Some(cmd) = stream.next() {
-            log_size += 1;
-            let cmd = cmd.map_err(|c| KvStoreError::DeserializationFailure { c })?;
+    /// Catches up with the writes of the store writing to the same directory,
+    /// for a store opened read-only. Does nothing for a writable store.
+    ///
+    /// Writes are caught up with in the order they were appended, thus a
+    /// write is only seen along with all writes preceding it.
+    ///
+    /// # Example
+    ///
+    /// ``` rust
+    /// use kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+    ///
+    /// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    /// assert_eq!(reader.get("key1".to_owned()).unwrap(), None);
+    ///
+    /// reader.refresh().unwrap();
+    /// assert_eq!(reader.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    /// ```
+    pub fn refresh(&self) -> Result<()> {
+        if self.compactor.is_some() {
+            return Ok(());
+        }
 
-            self.index.insert(cmd.key(), offset);
+        self.indexed_log.refresh()
+    }
 
-            offset = stream.byte_offset() as Offset;
+    /// Triggers compaction in the background if the log contains enough stale
+    /// data.
+    fn trigger_compaction(&self) {
+        if let Some(compactor) = &self.compactor {
+            if self.indexed_log.should_compact() {
+                compactor.trigger();
+            }
         }
+    }
+}
 
-        self.log_file.num_writes = log_size;
+/// Version is a single version of a key, as returned by `KvStore::history`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    /// Sequence number of the write of the version.
+    pub seq: u64,
+    /// Time of the write of the version in milliseconds since the unix
+    /// epoch. Only known for values, and not for those written by earlier
+    /// versions of the store.
+    pub time: Option<u64>,
+    /// Value written, `None` standing for a removal.
+    pub value: Option<Vec<u8>>,
+}
 
-        Ok(())
+/// Snapshot is a read-only view of a KvStore as of the time it was taken,
+/// returned by `KvStore::snapshot`.
+///
+/// # Example
+///
+/// ``` rust
+/// use kvs::KvStore;
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+///
+/// let snapshot = store.snapshot();
+/// store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+///
+/// assert_eq!(snapshot.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value2".to_owned()));
+/// ```
+pub struct Snapshot {
+    indexed_log: Arc<IndexedLog>,
+    // Sequence number of the last write seen by the snapshot.
+    seq: u64,
+}
+
+impl Snapshot {
+    /// Returns the value for the given key as of the snapshot.
+    pub fn get_bytes(&self, k: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let cmd = self.indexed_log.read_at(&k, Some(self.seq))?;
+
+        Ok(cmd.and_then(Command::into_value))
+    }
+
+    /// Returns the value for the given key as of the snapshot, failing on a
+    /// value that is not valid utf8.
+    pub fn get(&self, k: String) -> Result<Option<String>> {
+        self.get_bytes(k.into_bytes())?
+            .map(|v| String::from_utf8(v).map_err(|c| KvStoreError::Utf8Failure { c }))
+            .transpose()
     }
 }
 
-/// LogFile represents a database log file on disk.
-struct LogFile {
-    reader: std::io::BufReader<std::fs::File>,
-    // TODO: How about a buffered writer that we can flush once after
-    // compaction?
-    file: std::fs::File,
-    // Position within the file.
-    position: Offset,
-    num_writes: usize,
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.indexed_log.close_snapshot(self.seq) {
+            error!("failed to close snapshot: {}", e);
+        }
+    }
 }
 
-impl LogFile {
-    fn new(path: &std::path::Path) -> Result<LogFile> {
-        let path = path.join("db");
+/// Compactor runs log compaction on a dedicated background thread, thereby
+/// not stalling readers and writers for the duration of the rewrite. The same
+/// thread writes tombstones for expired keys and, with an interval sync
+/// policy, syncs writes that are left unsynced.
+///
+/// The thread stops once the last handle to the store is dropped.
+struct Compactor {
+    tx: Mutex<Option<Sender<CompactionRequest>>>,
+    // Whether a triggered compaction did not start yet.
+    pending: Arc<AtomicBool>,
+    handle: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
 
-        let mut write_file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(path.clone())
-            .map_err(|c| KvStoreError::OpenFileFailure {
-                c,
-                name: path.display().to_string(),
-            })?;
+enum CompactionRequest {
+    /// Compact if the log contains enough stale data.
+    Trigger,
+    /// Compact unconditionally, reporting the result.
+    Compact(Sender<Result<()>>),
+}
 
-        // Get end of file.
-        let position = write_file
-            .seek(std::io::SeekFrom::End(0))
-            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+impl Compactor {
+    fn spawn(indexed_log: Arc<IndexedLog>) -> Compactor {
+        let (tx, rx) = channel();
+        let pending = Arc::new(AtomicBool::new(false));
 
-        let read_file = std::fs::OpenOptions::new()
-            .read(true)
-            .open(path.clone())
-            .map_err(|c| KvStoreError::OpenFileFailure {
-                c,
-                name: path.display().to_string(),
-            })?;
-        let reader = std::io::BufReader::new(read_file);
+        let thread_pending = pending.clone();
+        let handle = std::thread::spawn(move || run_compactor(indexed_log, rx, thread_pending));
 
-        Ok(LogFile{
-            reader,
-            file: write_file,
-            position,
-            num_writes: 0,
-        })
+        Compactor {
+            tx: Mutex::new(Some(tx)),
+            pending,
+            handle: Mutex::new(Some(handle)),
+        }
     }
 
-    fn write_cmd(&mut self, cmd: Command) -> Result<Offset> {
-        let offset = self.position;
+    fn trigger(&self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
 
-        let serialized =
-            serde_json::to_string(&cmd).map_err(|c| KvStoreError::SerializationFailure { c })?;
+        self.send(CompactionRequest::Trigger);
+    }
 
-        self.position = offset + self.file.write(serialized.as_bytes())
-            .map(|p| p as Offset)
-            .map_err(|c| KvStoreError::WriteToFileFailure {
-                c,
-            })?;
+    fn compact(&self) -> Result<()> {
+        let (tx, rx) = channel();
 
-        self.file.flush()
-            .map_err(|c| KvStoreError::FileFlushFailure{c})?;
+        self.send(CompactionRequest::Compact(tx));
 
-        self.num_writes += 1;
+        rx.recv().map_err(|_| KvStoreError::CompactionStopped)?
+    }
 
-        Ok(offset)
+    fn send(&self, req: CompactionRequest) {
+        if let Some(tx) = self.tx.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+            // The thread only stops once the sender is dropped.
+            let _ = tx.send(req);
+        }
     }
+}
 
-    // TODO: Call this iter()?
-    fn get_reader(&mut self, offset: Offset) -> Result<&mut std::io::BufReader<std::fs::File>>{
-        self.reader
-            .seek(std::io::SeekFrom::Start(offset))
-            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once it is done with the
+        // current compaction.
+        drop(self.tx.lock().unwrap_or_else(PoisonError::into_inner).take());
 
-        Ok(&mut self.reader)
+        if let Some(handle) = self.handle.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            if handle.join().is_err() {
+                error!("compaction thread panicked");
+            }
+        }
     }
+}
 
-    fn read_cmd(&mut self, offset: Offset) -> Result<Option<Command>>  {
-        self.reader
-            .seek(std::io::SeekFrom::Start(offset ))
-            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+/// Follower catches up with the writes of the store writing to the same
+/// directory on a dedicated background thread, for a store opened read-only.
+///
+/// The thread stops once the last handle to the store is dropped.
+struct Follower {
+    tx: Mutex<Option<Sender<()>>>,
+    handle: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
 
-        let mut stream = serde_json::Deserializer::from_reader(&mut self.reader)
-            .into_iter::<Command>();
+impl Follower {
+    fn spawn(indexed_log: Arc<IndexedLog>, interval: Duration) -> Follower {
+        let (tx, rx) = channel::<()>();
+
+        let handle = std::thread::spawn(move || {
+            // Nothing is ever sent, the thread only stops once the sender is
+            // dropped.
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Err(e) = indexed_log.refresh() {
+                    error!("failed to follow log: {}", e);
+                }
+            }
+        });
+
+        Follower {
+            tx: Mutex::new(Some(tx)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+}
 
-        if let Some(cmd) = stream.next() {
-            let cmd = cmd.map_err(|c| KvStoreError::DeserializationFailure { c })?;
+impl Drop for Follower {
+    fn drop(&mut self) {
+        drop(self.tx.lock().unwrap_or_else(PoisonError::into_inner).take());
 
-            Ok(Some(cmd))
-        } else {
-            Ok(None)
+        if let Some(handle) = self.handle.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            if handle.join().is_err() {
+                error!("follower thread panicked");
+            }
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-enum Command {
-    Set { k: String, v: String },
-    Remove { k: String },
+fn run_compactor(
+    indexed_log: Arc<IndexedLog>,
+    rx: Receiver<CompactionRequest>,
+    pending: Arc<AtomicBool>,
+) {
+    let timeout = match indexed_log.options.sync_policy {
+        SyncPolicy::Interval(interval) => std::cmp::min(interval, REAP_INTERVAL),
+        _ => REAP_INTERVAL,
+    };
+    let mut reaped_at = Instant::now();
+
+    loop {
+        let req = match rx.recv_timeout(timeout) {
+            Ok(req) => Some(req),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Err(e) = indexed_log.sync_due() {
+            error!("failed to sync log: {}", e);
+        }
+
+        if reaped_at.elapsed() >= REAP_INTERVAL {
+            if let Err(e) = indexed_log.reap_expired() {
+                error!("failed to reap expired keys: {}", e);
+            }
+            if let Ok(mut writer) = indexed_log.lock_writer() {
+                indexed_log.prune_history(&mut writer);
+            }
+            reaped_at = Instant::now();
+        }
+
+        let req = match req {
+            Some(req) => req,
+            None => continue,
+        };
+
+        match req {
+            CompactionRequest::Trigger => {
+                pending.store(false, Ordering::SeqCst);
+
+                if !indexed_log.should_compact() {
+                    continue;
+                }
+
+                if let Err(e) = compact(&indexed_log, false) {
+                    error!("failed to compact log: {}", e);
+                }
+            }
+            CompactionRequest::Compact(reply) => {
+                let _ = reply.send(compact(&indexed_log, true));
+            }
+        }
+    }
 }
 
-impl Command {
-    fn key(&self) -> String {
-        match self {
-            Command::Set { k, .. } => k.to_string(),
-            Command::Remove { k } => k.to_string(),
+/// Cursor walks the keys of the index in ascending order.
+///
+/// It does not borrow the index, but looks up the key following the previous
+/// one on every step, thus it sees keys inserted while iterating as well.
+struct Cursor {
+    indexed_log: Arc<IndexedLog>,
+    next: Bound<Vec<u8>>,
+    end: Option<Vec<u8>>,
+}
+
+impl Cursor {
+    fn new(indexed_log: Arc<IndexedLog>, start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> Cursor {
+        let next = match start {
+            Some(start) => Bound::Included(start),
+            None => Bound::Unbounded,
+        };
+
+        Cursor {
+            indexed_log,
+            next,
+            end,
         }
     }
+}
 
-    fn value(&self) -> Option<String> {
-        match self {
-            Command::Set {  v, .. } => Some(v.to_string()),
-            Command::Remove { .. } => None,
+impl Iterator for Cursor {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let bound = match &self.next {
+                Bound::Included(k) => Bound::Included(k.as_slice()),
+                Bound::Excluded(k) => Bound::Excluded(k.as_slice()),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let entry = self.indexed_log.index.lower_bound(bound)?;
+            let key = entry.key().clone();
+            let expired = entry.value().is_expired(now_millis());
+
+            if let Some(end) = &self.end {
+                if key >= *end {
+                    return None;
+                }
+            }
+
+            self.next = Bound::Excluded(key.clone());
+
+            // Skip expired keys.
+            if !expired {
+                return Some(key);
+            }
         }
     }
 }
 
+/// Compacts the log, only holding the writer lock while planning the
+/// compaction and while swapping in the compacted segments.
+fn compact(indexed_log: &IndexedLog, forced: bool) -> Result<()> {
+    let plan = match indexed_log.plan_compaction(forced)? {
+        Some(plan) => plan,
+        None => return Ok(()),
+    };
+
+    let compaction = indexed_log.copy_records(&plan)?;
+
+    indexed_log.finish_compaction(&plan, compaction)?;
+
+    indexed_log.remove_sealed(&plan.sealed)
+}
+
+type Offset = u64;
+
+type SegmentId = u64;
+
+/// Location of a single record within the log, along with the sequence
+/// number of its write and the expiry of the value it holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Position {
+    segment: SegmentId,
+    offset: Offset,
+    len: u64,
+    seq: u64,
+    // Expiry in milliseconds since the unix epoch.
+    expires: Option<u64>,
+}
+
+impl Position {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the time at which a value written now with the given time to live
+/// expires, in milliseconds since the unix epoch.
+pub(crate) fn expiry_millis(ttl: Duration) -> Result<u64> {
+    u64::try_from(ttl.as_millis())
+        .ok()
+        .and_then(|ttl| now_millis().checked_add(ttl))
+        .ok_or(KvStoreError::TtlOverflow { ttl })
+}
+
+/// IndexedLog is a log split into numbered segments, together with an index
+/// of the latest record of each live key.
+///
+/// Segments are replayed in order of their id. A record supersedes the
+/// records of its key written before it, as told by their sequence numbers,
+/// as compacted segments hold older records than the segments preceding them.
+///
+/// Readers never take a lock. They look up the position of a record in the
+/// concurrent index and read it through a positional read on the shared
+/// segment file. Writers and compaction are serialized through `writer`,
+/// with concurrent writes being committed in groups.
+///
+/// While snapshots are open or history is retained, every write keeps the
+/// version it supersedes in `superseded`, under the key and the sequence
+/// number of the write. A snapshot reads the version superseded by the first
+/// write following it, or the latest version if there is none. Compaction
+/// copies these versions along with the live ones, separated by tombstones
+/// where a key was absent, thus replaying the compacted log restores them.
+///
+/// Each compacted segment comes with a hint file listing its records, thus
+/// opening the log only reads the segments written since the last compaction.
+struct IndexedLog {
+    path: PathBuf,
+    options: KvStoreOptions,
+    // Lock of the directory, held unless the log is opened read-only.
+    lock: Option<std::fs::File>,
+    // Sealed segments and the active segment.
+    segments: SkipMap<SegmentId, Arc<SegmentFile>>,
+    index: SkipMap<Vec<u8>, Position>,
+    superseded: Superseded,
+    // Sequence number at and before which superseded versions are dropped.
+    horizon: AtomicU64,
+    writer: Mutex<Writer>,
+    // Writes waiting to be committed with the next group.
+    queue: Mutex<Vec<PendingWrite>>,
+    watchers: Watchers,
+}
+
+/// Versions superseded by writes, by key and sequence number of the
+/// superseding write. `None` stands for an absent key.
+type Superseded = SkipMap<(Vec<u8>, u64), Option<Position>>;
+
+/// Condition for a write to happen.
+enum Condition {
+    /// Each key holds the expected value, `None` standing for an absent key.
+    Values(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    /// The key holds the expected value, `None` standing for an absent key.
+    /// The value set keeps the expiry of the value it replaces.
+    Swap { key: Vec<u8>, expected: Option<Vec<u8>> },
+    /// The value of the key expired. Only used to remove the key.
+    Expired { key: Vec<u8> },
+}
+
+/// PendingWrite is a write of one or more commands queued to be committed.
+struct PendingWrite {
+    cmds: Vec<Command>,
+    condition: Option<Condition>,
+    // Whether the write asks to be synced.
+    sync: bool,
+    done: Sender<Result<()>>,
+}
+
+/// Writer holds the state only needed on the write path.
+struct Writer {
+    // Segment appended to, none if the log is opened read-only.
+    active: Option<LogWriter>,
+    stats: LogStats,
+    // Sequence number of the last committed write.
+    seq: u64,
+    // Number of open snapshots by sequence number.
+    snapshots: BTreeMap<u64, usize>,
+    // Set once a failed write could not be cut off the log, leaving the log
+    // out of step with the state.
+    poisoned: bool,
+}
+
+/// LogStats tracks the amount of live and stale data, deciding when and what
+/// to compact, as well as the keys due to expire and the superseded versions
+/// due to be pruned.
+#[derive(Default)]
+struct LogStats {
+    // Length in bytes of each segment.
+    segment_lens: BTreeMap<SegmentId, u64>,
+    // Records held by each segment.
+    segments: BTreeMap<SegmentId, SegmentStats>,
+    // Sum of the length of all records referenced by the index.
+    live_bytes: u64,
+    // Sum of the length of all records of superseded versions.
+    history_bytes: u64,
+    // Expiry and key of all indexed values with an expiry, soonest first.
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    // Sequence number and key of all superseded versions, oldest first.
+    versions: BTreeSet<(u64, Vec<u8>)>,
+}
+
+/// SegmentStats tracks the records of a single segment.
+#[derive(Default)]
+struct SegmentStats {
+    // Sum of the length of its records referenced by the index or kept as
+    // superseded versions, as well as of the records kept by compaction
+    // without being indexed.
+    kept_bytes: u64,
+    // Lowest sequence number of its records.
+    min_seq: Option<u64>,
+    // Highest sequence number of its records.
+    max_seq: u64,
+    // Hashes of the keys of its records. Compaction checks those of the
+    // segments it leaves alone without holding the writer lock.
+    keys: Arc<HashSet<u64>>,
+    // Tombstones written by compaction.
+    tombstones: Vec<KeptTombstone>,
+}
+
+/// Tombstone written by compaction, counted as kept until neither the
+/// records it shadows nor the versions it separates are around anymore.
+struct KeptTombstone {
+    len: u64,
+    seq: u64,
+    // Whether the tombstone separates superseded versions.
+    history: bool,
+    // Segments that might hold older records of its key.
+    shadowed: Vec<SegmentId>,
+}
+
+impl LogStats {
+    /// Accounts for a record of the given key being part of the log.
+    fn add_record(&mut self, key: &[u8], position: &Position) {
+        let len = self.segment_lens.entry(position.segment).or_insert(0);
+        *len = std::cmp::max(*len, position.offset + position.len);
+
+        self.segments.entry(position.segment).or_default().add_record(key, position.seq);
+    }
+
+    /// Accounts for the record at the given position being kept.
+    fn keep(&mut self, position: &Position) {
+        self.segments.entry(position.segment).or_default().kept_bytes += position.len;
+    }
+
+    /// Accounts for the record at the given position no longer being kept.
+    fn release(&mut self, position: &Position) {
+        if let Some(segment) = self.segments.get_mut(&position.segment) {
+            segment.kept_bytes -= position.len;
+        }
+    }
+
+    /// Returns the amount of bytes of the given segment no longer needed.
+    fn stale_bytes(&self, id: SegmentId) -> u64 {
+        self.stale_bytes_dropping(id, 0)
+    }
+
+    /// Returns the amount of bytes of the given segment no longer needed,
+    /// once the given amount of its kept bytes is dropped.
+    fn stale_bytes_dropping(&self, id: SegmentId, dropped: u64) -> u64 {
+        let len = self.segment_lens.get(&id).cloned().unwrap_or(0);
+        let kept = self.segments.get(&id).map_or(0, |segment| segment.kept_bytes - dropped);
+
+        // Only a segment with records worth keeping needs its header.
+        if kept == 0 {
+            return len;
+        }
+
+        len.saturating_sub(kept + record::SEGMENT_HEADER_LEN)
+    }
+}
+
+impl SegmentStats {
+    fn add_record(&mut self, key: &[u8], seq: u64) {
+        self.min_seq = Some(self.min_seq.map_or(seq, |min_seq| std::cmp::min(min_seq, seq)));
+        self.max_seq = std::cmp::max(self.max_seq, seq);
+        Arc::make_mut(&mut self.keys).insert(key_hash(key));
+    }
+}
+
+/// Returns the hash of a key as tracked for each segment.
+fn key_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Writer {
+    fn active(&mut self) -> Result<&mut LogWriter> {
+        self.active.as_mut().ok_or(KvStoreError::ReadOnly)
+    }
+}
+
+impl IndexedLog {
+    /// Opens the log in the given directory.
+    ///
+    /// A torn write at the end of the log is cut off, and so are the segments
+    /// of a compaction run the process died in.
+    ///
+    /// A log opened read-only leaves the directory as is, including a torn
+    /// write at its end, which might just as well be a write in progress. It
+    /// is replayed the way `refresh` catches up with the writer.
+    fn open(path: &Path, options: KvStoreOptions, read_only: bool) -> Result<Self> {
+        let lock = if read_only {
+            None
+        } else {
+            let lock = crate::manifest::lock_dir(path)?;
+            adopt_legacy_log(path)?;
+            remove_incomplete_outputs(path)?;
+            Some(lock)
+        };
+
+        let mut ids = if read_only { Vec::new() } else { segment_ids(path)? };
+
+        let segments = SkipMap::new();
+        for id in &ids {
+            segments.insert(*id, Arc::new(SegmentFile::open(path, *id)?));
+        }
+
+        let index = SkipMap::new();
+        let superseded = SkipMap::new();
+        let mut stats = LogStats::default();
+        let mut seq = 0;
+        let mut replay = Replay {
+            index: &index,
+            superseded: &superseded,
+            history: options.history_retention > 0,
+            truncate: true,
+            stats: &mut stats,
+            seq: &mut seq,
+            removed: HashMap::new(),
+        };
+        for (i, id) in ids.iter().enumerate() {
+            let last = i == ids.len() - 1;
+            // The last segment might be appended to, thus never has a hint.
+            if last || !replay_hint(path, &mut replay, *id)? {
+                replay_segment(path, &segments, &mut replay, *id, last, 0)?;
+            }
+        }
+
+        let active = if read_only {
+            None
+        } else {
+            // New records are only ever appended in the current format.
+            let reuse_last = match segments.back() {
+                Some(entry) => entry.value().version == record::FORMAT_VERSION,
+                None => false,
+            };
+            if !reuse_last {
+                ids.push(ids.last().map_or(0, |id| id + 1));
+            }
+
+            let active = LogWriter::open(path, *ids.last().unwrap())?;
+            if !reuse_last {
+                segments.insert(active.segment, Arc::new(SegmentFile::open(path, active.segment)?));
+            }
+            stats.segment_lens.insert(active.segment, active.position);
+            Some(active)
+        };
+
+        let indexed_log = IndexedLog {
+            path: path.to_path_buf(),
+            options,
+            lock,
+            segments,
+            index,
+            superseded,
+            horizon: AtomicU64::new(0),
+            writer: Mutex::new(Writer {
+                active,
+                stats,
+                seq,
+                snapshots: BTreeMap::new(),
+                poisoned: false,
+            }),
+            queue: Mutex::new(Vec::new()),
+            watchers: Watchers::default(),
+        };
+        if read_only {
+            indexed_log.refresh()?;
+        }
+        indexed_log.prune_history(&mut *indexed_log.lock_writer()?);
+
+        Ok(indexed_log)
+    }
+
+    /// Locks the state of the writer in order to write to the log. Fails once
+    /// a thread panicked while holding the lock, or a failed write could not
+    /// be rolled back, as the state might not match the log anymore.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        match self.writer.lock() {
+            Ok(writer) if !writer.poisoned => Ok(writer),
+            _ => Err(KvStoreError::Poisoned),
+        }
+    }
+
+    /// Locks the state of the writer in order to read it, which a panicked
+    /// writer does not get in the way of.
+    fn read_writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Catches up with the writes appended by the writer of the directory
+    /// since the log was opened or last refreshed. Only used by logs opened
+    /// read-only.
+    ///
+    /// Once the writer compacted the log, the whole log is replayed anew and
+    /// swapped in key by key. Readers never see a key go back to an older
+    /// value, but snapshots lose the versions not kept by the writer.
+    fn refresh(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        let mut replay_all = false;
+
+        loop {
+            let ids = segment_ids(&self.path)?;
+            let skipped = match self.catch_up(&mut writer, &ids, replay_all) {
+                Ok(skipped) => skipped,
+                // The writer removing segments while they are replayed fails
+                // the replay, which then goes on with the remaining segments.
+                Err(e) => {
+                    if segment_ids(&self.path)? == ids {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            // A compaction finishing during the replay might have removed
+            // segments skipped as compacted, or not listed at all, with their
+            // records moved to an output not listed yet. Such outputs show up
+            // in between the segments listed, and skipped ones get a hint.
+            let tail = ids.last().cloned().unwrap_or(0);
+            let relisted = segment_ids(&self.path)?;
+            let moved = relisted.iter().any(|id| *id < tail && ids.binary_search(id).is_err())
+                || skipped.iter().any(|id| hint_path(&self.path, *id).exists());
+            if !moved {
+                return Ok(());
+            }
+            replay_all = true;
+        }
+    }
+
+    /// Catches up with the writes within the given segments, replaying them
+    /// all anew if `replay_all` is set. Returns the ids of the compacted
+    /// segments skipped.
+    fn catch_up(&self, writer: &mut Writer, ids: &[SegmentId], replay_all: bool) -> Result<Vec<SegmentId>> {
+        let history = !writer.snapshots.is_empty() || self.options.history_retention > 0;
+
+        // Compaction only removes the segments it compacted once it is done.
+        let compacted = self.segments.iter().any(|entry| ids.binary_search(entry.key()).is_err());
+        if !compacted && !replay_all {
+            let mut replay = Replay {
+                index: &self.index,
+                superseded: &self.superseded,
+                history,
+                truncate: false,
+                stats: &mut writer.stats,
+                seq: &mut writer.seq,
+                removed: HashMap::new(),
+            };
+            return follow_segments(&self.path, &self.segments, &mut replay, ids);
+        }
+
+        let segments = SkipMap::new();
+        let index = SkipMap::new();
+        let superseded = SkipMap::new();
+        let mut stats = LogStats::default();
+        let mut seq = 0;
+        let mut replay = Replay {
+            index: &index,
+            superseded: &superseded,
+            history,
+            truncate: false,
+            stats: &mut stats,
+            seq: &mut seq,
+            removed: HashMap::new(),
+        };
+        let skipped = follow_segments(&self.path, &segments, &mut replay, ids)?;
+
+        // Entries are replaced before stale ones are removed, thus readers
+        // finding a segment gone find the key moved as well.
+        for entry in segments.iter() {
+            self.segments.insert(*entry.key(), entry.value().clone());
+        }
+        for entry in index.iter() {
+            self.index.insert(entry.key().clone(), *entry.value());
+        }
+        for entry in superseded.iter() {
+            self.superseded.insert(entry.key().clone(), *entry.value());
+        }
+        for entry in self.index.iter() {
+            if !index.contains_key(entry.key()) {
+                entry.remove();
+            }
+        }
+        for entry in self.superseded.iter() {
+            if !superseded.contains_key(entry.key()) {
+                entry.remove();
+            }
+        }
+        for entry in self.segments.iter() {
+            if !segments.contains_key(entry.key()) {
+                entry.remove();
+            }
+        }
+        writer.stats = stats;
+        writer.seq = seq;
+
+        Ok(skipped)
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<Command>> {
+        self.read_at(key, None)
+    }
+
+    /// Returns the latest command of the given key, or the one as of the
+    /// snapshot with the given sequence number.
+    fn read_at(&self, key: &[u8], seq: Option<u64>) -> Result<Option<Command>> {
+        loop {
+            let position = match self.lookup(key, seq) {
+                Some(position) => position,
+                None => return Ok(None),
+            };
+
+            // Expired keys stay in the index until reaped.
+            if position.is_expired(now_millis()) {
+                return Ok(None);
+            }
+
+            // Compaction might have removed the segment in between looking up
+            // the position and reading the record. In that case the index
+            // already points to the compacted record.
+            let segment = match self.segments.get(&position.segment) {
+                Some(entry) => entry.value().clone(),
+                None if self.lookup(key, seq) != Some(position) => continue,
+                None => return Err(KvStoreError::SegmentNotFound { id: position.segment }),
+            };
+
+            return segment.read_command(position).map(Some);
+        }
+    }
+
+    /// Returns the position of the latest record of the given key, or the one
+    /// as of the snapshot with the given sequence number.
+    fn lookup(&self, key: &[u8], seq: Option<u64>) -> Option<Position> {
+        // The index is read first, as writes record the superseded version
+        // before updating the index.
+        let latest = self.index.get(key).map(|e| *e.value());
+
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return latest,
+        };
+
+        match self.superseded.lower_bound(Bound::Excluded(&(key.to_vec(), seq))) {
+            Some(entry) if entry.key().0 == key => *entry.value(),
+            _ => latest,
+        }
+    }
+
+    /// Opens a snapshot of all writes committed so far, returning its
+    /// sequence number.
+    fn open_snapshot(&self) -> u64 {
+        let mut writer = self.read_writer();
+
+        let seq = writer.seq;
+        *writer.snapshots.entry(seq).or_insert(0) += 1;
+
+        seq
+    }
+
+    /// Closes a snapshot, dropping the versions only kept for it.
+    fn close_snapshot(&self, seq: u64) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+
+        if let Some(count) = writer.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                writer.snapshots.remove(&seq);
+            }
+        }
+
+        self.prune_history(&mut writer);
+
+        Ok(())
+    }
+
+    /// Drops the superseded versions neither seen by an open snapshot nor
+    /// within the retained history.
+    fn prune_history(&self, writer: &mut Writer) {
+        let retained = writer.seq.saturating_sub(self.options.history_retention);
+        let horizon = match writer.snapshots.keys().next() {
+            Some(oldest) => std::cmp::min(*oldest, retained),
+            None => retained,
+        };
+
+        // Readers check the horizon after reading, thus it is raised before
+        // dropping any version.
+        self.horizon.fetch_max(horizon, Ordering::SeqCst);
+
+        let retained = writer.stats.versions.split_off(&(horizon + 1, Vec::new()));
+        let pruned = std::mem::replace(&mut writer.stats.versions, retained);
+        for (seq, key) in pruned {
+            if let Some(entry) = self.superseded.remove(&(key, seq)) {
+                if let Some(position) = entry.value() {
+                    writer.stats.history_bytes -= position.len;
+                    writer.stats.release(position);
+                }
+            }
+        }
+    }
+
+    /// Returns the oldest sequence number the value of a key can be read as
+    /// of.
+    fn history_horizon(&self) -> u64 {
+        // Without retained history, versions are only kept for snapshots.
+        if self.options.history_retention == 0 {
+            return self.read_writer().seq;
+        }
+
+        self.horizon.load(Ordering::SeqCst)
+    }
+
+    /// Returns the superseded versions of the given key followed by the
+    /// current one.
+    fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        'retry: loop {
+            let mut positions = Vec::new();
+            {
+                // Writes and compaction update versions under the writer
+                // lock, thus holding it gives a consistent view.
+                let _writer = self.read_writer();
+
+                // A version without a value is a removal by the previous
+                // superseding write, if any.
+                let mut previous = None;
+                let range = (key.to_vec(), 0)..=(key.to_vec(), u64::MAX);
+                for entry in self.superseded.range(range) {
+                    match (*entry.value(), previous) {
+                        (Some(position), _) => positions.push((position.seq, Some(position))),
+                        (None, Some(seq)) => positions.push((seq, None)),
+                        (None, None) => {}
+                    }
+                    previous = Some(entry.key().1);
+                }
+
+                match (self.index.get(key), previous) {
+                    (Some(entry), _) => positions.push((entry.value().seq, Some(*entry.value()))),
+                    (None, Some(seq)) => positions.push((seq, None)),
+                    (None, None) => {}
+                }
+            }
+
+            let now = now_millis();
+            let mut history = Vec::with_capacity(positions.len());
+            for (seq, position) in positions {
+                let (time, value) = match position {
+                    Some(position) if !position.is_expired(now) => {
+                        // Compaction might have moved the record in between.
+                        let segment = match self.segments.get(&position.segment) {
+                            Some(entry) => entry.value().clone(),
+                            None => continue 'retry,
+                        };
+                        let record = segment.read_record(position)?;
+                        // Records of earlier versions carry no time.
+                        (Some(record.time).filter(|time| *time > 0), record.cmd.into_value())
+                    }
+                    _ => (None, None),
+                };
+                history.push(Version { seq, time, value });
+            }
+
+            return Ok(history);
+        }
+    }
+
+    /// Writes the given commands atomically, returning once they are
+    /// committed. Multiple commands are preceded by a batch marker.
+    ///
+    /// Concurrent writes are committed in groups. Each writer queues its
+    /// records, then whichever writer gets hold of the writer lock commits all
+    /// queued records with a single write and at most a single sync. Writers
+    /// whose records got committed by another writer only wait for the outcome.
+    fn write(&self, cmds: Vec<Command>, condition: Option<Condition>, options: &WriteOptions) -> Result<()> {
+        if self.lock.is_none() {
+            return Err(KvStoreError::ReadOnly);
+        }
+        for cmd in &cmds {
+            self.check_size(cmd)?;
+        }
+
+        let (done, outcome) = channel();
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner).push(PendingWrite {
+            cmds,
+            condition,
+            sync: options.sync,
+            done,
+        });
+
+        {
+            let writer = self.lock_writer();
+            let group = std::mem::take(&mut *self.queue.lock().unwrap_or_else(PoisonError::into_inner));
+            // Writes queued by others fail along with this one.
+            let mut writer = writer?;
+            if !group.is_empty() {
+                self.commit(&mut writer, group);
+            }
+        }
+
+        outcome.recv().map_err(|_| KvStoreError::CommitFailed {
+            reason: "committing writer panicked".to_owned(),
+        })?
+    }
+
+    /// Checks the key and value of a write against the configured limits.
+    fn check_size(&self, cmd: &Command) -> Result<()> {
+        let (k, v) = match cmd {
+            Command::Set { k, v, .. } => (k, Some(v)),
+            Command::Remove { k } => (k, None),
+            Command::Batch { .. } | Command::Sequence => return Ok(()),
+        };
+
+        if let Some(max) = self.options.max_key_size {
+            if k.len() > max {
+                return Err(KvStoreError::KeyTooLarge { len: k.len(), max });
+            }
+        }
+        if let (Some(max), Some(v)) = (self.options.max_value_size, v) {
+            if v.len() > max {
+                return Err(KvStoreError::ValueTooLarge { len: v.len(), max });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Commits a group of queued writes, reporting the outcome to each writer.
+    fn commit(&self, writer: &mut Writer, mut group: Vec<PendingWrite>) {
+        match self.append_group(writer, &mut group) {
+            Ok(outcomes) => {
+                for (write, outcome) in group.into_iter().zip(outcomes) {
+                    let _ = write.done.send(outcome);
+                }
+            }
+            Err(e) => {
+                let reason = match e.cause() {
+                    Some(cause) => format!("{}: {}", e, cause),
+                    None => e.to_string(),
+                };
+
+                for write in group {
+                    let _ = write.done.send(Err(KvStoreError::CommitFailed {
+                        reason: reason.clone(),
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Appends the records of a group of writes to the log and syncs them if
+    /// needed. A write failing its checks, e.g. by removing a key that does
+    /// not exist, only fails itself, including all other commands of its
+    /// batch.
+    ///
+    /// Should appending fail, the log is cut back to the last committed write,
+    /// so none of the writes of the group are replayed later on. The writer
+    /// is poisoned if that fails as well.
+    fn append_group(&self, writer: &mut Writer, group: &mut [PendingWrite]) -> Result<Vec<Result<()>>> {
+        let active = writer.active()?;
+        let (segment, position, seq) = (active.segment, active.position, writer.seq);
+
+        self.append_records(writer, group).inspect_err(|_| {
+            if let Err(e) = self.roll_back(writer, segment, position, seq) {
+                error!("Failed to roll back a failed write: {}", e);
+                writer.poisoned = true;
+            }
+        })
+    }
+
+    /// Cuts the log back to the given position of the given segment after
+    /// appending a group of writes failed, dropping the segments the group
+    /// rotated to.
+    fn roll_back(&self, writer: &mut Writer, segment: SegmentId, position: Offset, seq: u64) -> Result<()> {
+        writer.seq = seq;
+        writer.active = None;
+
+        // The active segment is the last one, later ones were created by the
+        // group, if only partially.
+        for id in file_ids(&self.path, "log")? {
+            if id <= segment {
+                continue;
+            }
+
+            self.segments.remove(&id);
+            writer.stats.segment_lens.remove(&id);
+            let path = segment_path(&self.path, id);
+            std::fs::remove_file(&path).map_err(|c| KvStoreError::FileRemoveFailure {
+                c,
+                name: path.display().to_string(),
+            })?;
+        }
+
+        truncate_segment(&segment_path(&self.path, segment), position)?;
+        writer.active = Some(LogWriter::open(&self.path, segment)?);
+
+        Ok(())
+    }
+
+    /// Appends the records of a group of writes, see `append_group`.
+    fn append_records(&self, writer: &mut Writer, group: &mut [PendingWrite]) -> Result<Vec<Result<()>>> {
+        // Values and expiry of keys set or removed earlier within the group.
+        let mut live: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+        let mut expiries: HashMap<&[u8], Option<u64>> = HashMap::new();
+        let mut positions = Vec::with_capacity(group.len());
+        let mut outcomes = Vec::with_capacity(group.len());
+        let mut buf = Vec::new();
+        let mut sync = false;
+        let time = now_millis();
+
+        for write in group.iter_mut() {
+            // A swap keeps the expiry of the value it replaces.
+            if let Some(Condition::Swap { key, .. }) = &write.condition {
+                let expires = match expiries.get(key.as_slice()) {
+                    Some(expires) => *expires,
+                    // An expired value counts as absent.
+                    None => self
+                        .index
+                        .get(key)
+                        .and_then(|e| e.value().expires.filter(|expires| *expires > time)),
+                };
+                for cmd in &mut write.cmds {
+                    if let Command::Set { e, .. } = cmd {
+                        *e = expires;
+                    }
+                }
+            }
+            let write: &PendingWrite = write;
+
+            match self.check_write(&live, write) {
+                Ok(changes) => live.extend(changes),
+                Err(e) => {
+                    outcomes.push(Err(e));
+                    continue;
+                }
+            }
+            // A write without commands only checks its condition, taking no
+            // sequence number.
+            if write.cmds.is_empty() {
+                outcomes.push(Ok(()));
+                continue;
+            }
+            for cmd in &write.cmds {
+                if let Command::Set { k, .. } | Command::Remove { k } = cmd {
+                    expiries.insert(k.as_slice(), cmd.expires());
+                }
+            }
+
+            // All commands of a write share its sequence number.
+            let seq = writer.seq + 1;
+            let mut records = Vec::new();
+            if write.cmds.len() > 1 {
+                let count = write.cmds.len() as u64;
+                records.extend(encode(seq, time, &Command::Batch { count })?);
+            }
+            let mut lens = Vec::with_capacity(write.cmds.len());
+            for cmd in &write.cmds {
+                let record = encode(seq, time, cmd)?;
+                lens.push(record.len() as u64);
+                records.extend(record);
+            }
+
+            // All records of a write end up in the same segment.
+            let len = records.len() as u64;
+            let offset = writer.active()?.position + buf.len() as u64;
+            if offset > 0 && offset + len > self.options.max_segment_size {
+                writer.active()?.write(&buf)?;
+                buf.clear();
+
+                let next = writer.active()?.segment + 1;
+                self.rotate(writer, next)?;
+            }
+
+            writer.seq = seq;
+
+            // Commands follow the batch marker, if any.
+            let marker_len = len - lens.iter().sum::<u64>();
+            let mut offset = writer.active()?.position + buf.len() as u64 + marker_len;
+            for (cmd, len) in write.cmds.iter().zip(&lens) {
+                positions.push((
+                    cmd,
+                    Position {
+                        segment: writer.active()?.segment,
+                        offset,
+                        len: *len,
+                        seq,
+                        expires: cmd.expires(),
+                    },
+                ));
+                offset += len;
+            }
+            buf.extend_from_slice(&records);
+
+            sync |= write.sync;
+            outcomes.push(Ok(()));
+        }
+
+        if !buf.is_empty() {
+            writer.active()?.write(&buf)?;
+        }
+
+        self.sync_write(writer, sync)?;
+
+        // Readers only get to see records once they are synced as requested.
+        let superseded = if writer.snapshots.is_empty() && self.options.history_retention == 0 {
+            None
+        } else {
+            Some(&self.superseded)
+        };
+        for (cmd, position) in &positions {
+            apply(&self.index, superseded, &mut writer.stats, cmd.key(), *position, cmd.is_set());
+        }
+
+        // Watches see writes in commit order, as they are notified under the
+        // writer lock, and only once readers see the whole group.
+        for (cmd, position) in positions {
+            let key = cmd.key();
+            self.watchers.notify(&key, || match cmd {
+                Command::Set { v, .. } => WatchEvent::Set {
+                    key: key.clone(),
+                    value: v.clone(),
+                    seq: Some(position.seq),
+                    time: Some(time),
+                },
+                _ => WatchEvent::Remove {
+                    key: key.clone(),
+                    seq: Some(position.seq),
+                    time: Some(time),
+                },
+            });
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Checks a write against the index and the writes earlier within its
+    /// group, returning the values it assigns to keys.
+    fn check_write<'a>(
+        &self,
+        live: &HashMap<&'a [u8], Option<&'a [u8]>>,
+        write: &'a PendingWrite,
+    ) -> Result<HashMap<&'a [u8], Option<&'a [u8]>>> {
+        let now = now_millis();
+        let mut changes = HashMap::new();
+
+        let holds = |key: &[u8], expected: &Option<Vec<u8>>| -> Result<bool> {
+            let current = match live.get(key) {
+                Some(v) => v.map(<[u8]>::to_vec),
+                None => self.read(key)?.and_then(Command::into_value),
+            };
+            Ok(current == *expected)
+        };
+
+        match &write.condition {
+            Some(Condition::Values(expected)) => {
+                for (key, expected) in expected {
+                    if !holds(key, expected)? {
+                        return Err(KvStoreError::ConditionFailed);
+                    }
+                }
+            }
+            Some(Condition::Swap { key, expected }) if !holds(key, expected)? => {
+                return Err(KvStoreError::ConditionFailed);
+            }
+            Some(Condition::Expired { key }) => {
+                // The key is not to be checked for existence, as expired keys
+                // count as absent.
+                let expired = !live.contains_key(key.as_slice())
+                    && self.index.get(key).is_some_and(|e| e.value().is_expired(now));
+                if !expired {
+                    return Err(KvStoreError::ConditionFailed);
+                }
+
+                changes.insert(key.as_slice(), None);
+                return Ok(changes);
+            }
+            Some(Condition::Swap { .. }) | None => {}
+        }
+
+        for cmd in &write.cmds {
+            match cmd {
+                Command::Set { k, v, .. } => {
+                    changes.insert(k.as_slice(), Some(v.as_slice()));
+                }
+                Command::Remove { k } => {
+                    let exists = match changes.get(k.as_slice()).or_else(|| live.get(k.as_slice())) {
+                        Some(v) => v.is_some(),
+                        None => self.index.get(k).is_some_and(|e| !e.value().is_expired(now)),
+                    };
+                    if !exists {
+                        return Err(KvStoreError::KeyNotFound);
+                    }
+                    changes.insert(k.as_slice(), None);
+                }
+                Command::Batch { .. } | Command::Sequence => unreachable!("markers are never queued"),
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Syncs the active segment after a write if either the write or the sync
+    /// policy asks for it.
+    fn sync_write(&self, writer: &mut Writer, force: bool) -> Result<()> {
+        let active = writer.active()?;
+        if active.unsynced_bytes == 0 {
+            return Ok(());
+        }
+
+        let due = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => active.synced_at.elapsed() >= interval,
+            SyncPolicy::Bytes(bytes) => active.unsynced_bytes >= bytes,
+        };
+
+        if force || due {
+            active.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs all writes to disk.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+
+        writer.active()?.sync()
+    }
+
+    /// Syncs any unsynced writes older than the sync interval.
+    fn sync_due(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+
+        if let SyncPolicy::Interval(interval) = self.options.sync_policy {
+            let active = writer.active()?;
+            if active.unsynced_bytes > 0 && active.synced_at.elapsed() >= interval {
+                active.sync()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes tombstones for all keys whose value expired.
+    fn reap_expired(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = writer
+            .stats
+            .expiries
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .map(|(_, key)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        let mut group = Vec::with_capacity(expired.len());
+        for key in expired {
+            // Nobody waits for the outcome.
+            let (done, _) = channel();
+            group.push(PendingWrite {
+                cmds: vec![Command::Remove { k: key.clone() }],
+                condition: Some(Condition::Expired { key }),
+                sync: false,
+                done,
+            });
+        }
+
+        self.append_group(&mut writer, &mut group)?;
+
+        Ok(())
+    }
+
+    /// Seals the active segment and continues writing to segment `id`.
+    fn rotate(&self, writer: &mut Writer, id: SegmentId) -> Result<()> {
+        writer.active()?.sync()?;
+
+        let active = LogWriter::open(&self.path, id)?;
+        self.segments.insert(id, Arc::new(SegmentFile::open(&self.path, id)?));
+        writer.stats.segment_lens.insert(id, active.position);
+        writer.active = Some(active);
+
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        let writer = self.read_writer();
+
+        // Superseded versions are carried over by compaction.
+        let stale_bytes: u64 = writer
+            .stats
+            .segment_lens
+            .keys()
+            .map(|id| writer.stats.stale_bytes(*id))
+            .sum();
+
+        stale_bytes >= self.options.compaction_min_bytes
+            && stale_bytes as f64 > writer.stats.live_bytes as f64 * self.options.compaction_ratio
+    }
+
+    /// Picks the segments to compact and seals the active segment, returning
+    /// `None` if there is nothing to compact.
+    ///
+    /// Segments are picked by the amount of their stale bytes, up to the
+    /// configured amount of data per run. Unless forced, a run leaves alone
+    /// segments holding less stale data than the compaction ratio asks for,
+    /// unless none holds enough, in which case it picks the one with the most
+    /// stale data. Segments of tombstones only kept for segments picked are
+    /// picked along.
+    ///
+    /// Compacted segments get ids in between the sealed segments and the new
+    /// active segment. They hold older writes than the segments they follow,
+    /// which replay orders by their sequence number.
+    fn plan_compaction(&self, forced: bool) -> Result<Option<CompactionPlan>> {
+        let mut writer = self.lock_writer()?;
+        let horizon = self.horizon.load(Ordering::SeqCst);
+
+        // Tombstones are no longer worth keeping once the segments they
+        // shadow are gone.
+        let LogStats { segment_lens, segments, .. } = &mut writer.stats;
+        for segment in segments.values_mut() {
+            let SegmentStats { kept_bytes, tombstones, .. } = segment;
+            tombstones.retain(|tombstone| {
+                let kept = (tombstone.history && tombstone.seq > horizon)
+                    || tombstone.shadowed.iter().any(|id| segment_lens.contains_key(id));
+                if !kept {
+                    *kept_bytes -= tombstone.len;
+                }
+                kept
+            });
+        }
+
+        // Records of segments written by earlier versions lack a sequence
+        // number, replay numbers them in log order. Thus those are compacted
+        // all at once.
+        let mut sealed: Vec<SegmentId> = self
+            .segments
+            .iter()
+            .filter(|entry| entry.value().version != record::FORMAT_VERSION)
+            .map(|entry| *entry.key())
+            .collect();
+        let mut bytes: u64 = sealed.iter().map(|id| writer.stats.segment_lens[id]).sum();
+
+        let mut candidates: Vec<(u64, SegmentId)> = writer
+            .stats
+            .segment_lens
+            .keys()
+            .filter(|id| sealed.binary_search(id).is_err())
+            .map(|id| (writer.stats.stale_bytes(*id), *id))
+            .filter(|(stale, _)| *stale > 0)
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+
+        let first = candidates.first().map(|(_, id)| *id);
+        for (stale, id) in candidates {
+            let len = writer.stats.segment_lens[&id];
+            if !forced && stale as f64 <= (len - stale) as f64 * self.options.compaction_ratio {
+                continue;
+            }
+            if !sealed.is_empty() && bytes + len > self.options.compaction_max_bytes {
+                continue;
+            }
+
+            sealed.push(id);
+            bytes += len;
+        }
+        if let (true, Some(id)) = (sealed.is_empty(), first) {
+            sealed.push(id);
+            bytes += writer.stats.segment_lens[&id];
+        }
+
+        // The run drops the tombstones shadowing only segments it compacts,
+        // which might leave the segments holding them stale enough to be
+        // compacted along.
+        loop {
+            let mut folded = Vec::new();
+            for (id, segment) in &writer.stats.segments {
+                if sealed.contains(id) {
+                    continue;
+                }
+
+                let dropped: u64 = segment
+                    .tombstones
+                    .iter()
+                    .filter(|tombstone| !(tombstone.history && tombstone.seq > horizon))
+                    .filter(|tombstone| tombstone.shadowed.iter().all(|id| sealed.contains(id)))
+                    .map(|tombstone| tombstone.len)
+                    .sum();
+                if dropped == 0 {
+                    continue;
+                }
+
+                let len = writer.stats.segment_lens[id];
+                let stale = writer.stats.stale_bytes_dropping(*id, dropped);
+                if !forced && stale as f64 <= (len - stale) as f64 * self.options.compaction_ratio {
+                    continue;
+                }
+                if bytes + len > self.options.compaction_max_bytes {
+                    continue;
+                }
+
+                folded.push(*id);
+                bytes += len;
+            }
+            if folded.is_empty() {
+                break;
+            }
+            sealed.extend(folded);
+        }
+
+        // Compacting nothing but a segment holding just the tombstones it
+        // keeps would only write them anew.
+        if let [id] = sealed[..] {
+            if let Some(segment) = writer.stats.segments.get(&id) {
+                let tombstones: u64 = segment.tombstones.iter().map(|tombstone| tombstone.len).sum();
+                if tombstones > 0 && segment.kept_bytes == tombstones {
+                    return Ok(None);
+                }
+            }
+        }
+
+        if sealed.is_empty() {
+            return Ok(None);
+        }
+        sealed.sort();
+
+        let seq = writer.seq;
+
+        // Compaction never produces more data than it consumes, thus there is
+        // at most one compacted segment for each sealed segment.
+        let first_output = writer.active()?.segment + 1;
+        let last_output = first_output + sealed.len() as u64 - 1;
+        self.rotate(&mut writer, last_output + 1)?;
+
+        let others: Vec<(&SegmentId, &SegmentStats)> = writer
+            .stats
+            .segments
+            .iter()
+            .filter(|(id, _)| sealed.binary_search(id).is_err())
+            .collect();
+        let carry_seq = others.iter().all(|(_, segment)| segment.max_seq < seq);
+        let others = others
+            .into_iter()
+            .filter_map(|(id, segment)| segment.min_seq.map(|min_seq| (*id, min_seq, segment.keys.clone())))
+            .collect();
+
+        Ok(Some(CompactionPlan {
+            sealed,
+            seq,
+            carry_seq,
+            first_output,
+            last_output,
+            others,
+        }))
+    }
+
+    /// Copies the records to be kept into new segments, reading the sealed
+    /// segments in order. Each compacted segment gets a hint file listing its
+    /// records, once the segment is synced.
+    ///
+    /// A record is kept if it is referenced by the index or is a superseded
+    /// version, unless it expired. A tombstone is kept while it might shadow
+    /// an older record of its key in another segment, or separates
+    /// superseded versions. So is a tombstone taking the place of an expired
+    /// record.
+    ///
+    /// Sealed segments are never written to, thus this does not need to hold
+    /// the writer lock.
+    fn copy_records(&self, plan: &CompactionPlan) -> Result<Compaction> {
+        let mut output = CompactionOutput {
+            dir: &self.path,
+            max_segment_size: self.options.max_segment_size,
+            seq: plan.seq,
+            next: plan.first_output,
+            last_output: plan.last_output,
+            writer: None,
+            hint: Vec::new(),
+            stats: SegmentStats::default(),
+            sealed: Vec::new(),
+        };
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let now = now_millis();
+
+        // The records of the latest writes might be dropped.
+        if plan.carry_seq {
+            output.start()?;
+        }
+
+        // Sequence numbers replay assigns to writes of earlier versions.
+        let mut counted = 0;
+        let mut batch = None;
+
+        for id in &plan.sealed {
+            let segment = match self.segments.get(id) {
+                Some(entry) => entry.value().clone(),
+                None => return Err(KvStoreError::SegmentNotFound { id: *id }),
+            };
+
+            for (offset, record) in segment.read_records(*id)? {
+                let seq = match (&record.cmd, &mut batch) {
+                    (Command::Sequence, _) => continue,
+                    (Command::Batch { count }, _) => {
+                        let seq = if record.seq == 0 {
+                            counted += 1;
+                            counted
+                        } else {
+                            record.seq
+                        };
+                        batch = Some((seq, *count));
+                        continue;
+                    }
+                    (_, Some((seq, count))) => {
+                        let seq = *seq;
+                        *count -= 1;
+                        if *count == 0 {
+                            batch = None;
+                        }
+                        seq
+                    }
+                    (_, None) if record.seq == 0 => {
+                        counted += 1;
+                        counted
+                    }
+                    (_, None) => record.seq,
+                };
+
+                let key = record.cmd.key();
+                let is_at = |position: &Position| position.segment == *id && position.offset == offset;
+                let current = self.index.get(&key).map(|e| *e.value());
+                let version = (key.clone(), seq);
+
+                if !record.cmd.is_set() {
+                    let history = self.superseded.contains_key(&version);
+                    let shadowed = if current.is_none() { plan.shadowed(&key, seq) } else { Vec::new() };
+                    if history || !shadowed.is_empty() {
+                        let tombstone = encode(seq, record.time, &record.cmd)?;
+                        output.write_tombstone(&key, &tombstone, seq, history, shadowed)?;
+                    }
+                    continue;
+                }
+
+                // The record is either the current version of its key, or
+                // the version superseded by the following write.
+                let old = match current {
+                    Some(current) if is_at(&current) => Some((current, true)),
+                    _ => match self.superseded.lower_bound(Bound::Excluded(&version)) {
+                        Some(entry) if entry.key().0 == key => match entry.value() {
+                            Some(position) if is_at(position) => Some((*position, false)),
+                            _ => None,
+                        },
+                        _ => None,
+                    },
+                };
+                let (old, live) = match old {
+                    Some(old) => old,
+                    None => continue,
+                };
+
+                if old.is_expired(now) {
+                    if live {
+                        expired.push((key.clone(), old));
+                    }
+
+                    // Replay must not go back to an older version. The time
+                    // of the tombstone is not known.
+                    let history = self.superseded.contains_key(&version);
+                    let shadowed = plan.shadowed(&key, seq);
+                    if history || !shadowed.is_empty() {
+                        let tombstone = encode(seq, 0, &Command::Remove { k: key.clone() })?;
+                        output.write_tombstone(&key, &tombstone, seq, history, shadowed)?;
+                    }
+                    continue;
+                }
+
+                // Records are encoded anew, thereby moving records of
+                // segments written by earlier versions to the current format.
+                let copy = encode(seq, record.time, &record.cmd)?;
+                let new = output.write(&key, &copy, seq, old.expires, HintKind::Set)?;
+                moved.push((key, old, new));
+            }
+        }
+
+        Ok(Compaction {
+            moved,
+            expired,
+            outputs: output.finish()?,
+        })
+    }
+
+    /// Swaps in the compacted segments. Keys written while compaction was
+    /// running keep pointing to their newer records.
+    fn finish_compaction(&self, plan: &CompactionPlan, compaction: Compaction) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+
+        for (id, stats) in compaction.outputs {
+            let segment = SegmentFile::open(&self.path, id)?;
+            writer.stats.segment_lens.insert(id, segment.len()?);
+            writer.stats.segments.insert(id, stats);
+            self.segments.insert(id, Arc::new(segment));
+        }
+
+        let mut moved_to = HashMap::with_capacity(compaction.moved.len());
+        for (key, old, new) in compaction.moved {
+            if self.index.get(&key).map(|e| *e.value()) == Some(old) {
+                self.index.insert(key, new);
+                writer.stats.live_bytes -= old.len;
+                writer.stats.live_bytes += new.len;
+                writer.stats.keep(&new);
+            }
+            moved_to.insert((old.segment, old.offset), new);
+        }
+
+        // Superseded versions follow their records, including versions
+        // superseded while compaction was running. Versions not copied
+        // expired and read as absent.
+        for entry in self.superseded.iter() {
+            let old = match entry.value() {
+                Some(old) if plan.sealed.binary_search(&old.segment).is_ok() => *old,
+                _ => continue,
+            };
+            let new = moved_to.get(&(old.segment, old.offset)).cloned();
+
+            writer.stats.history_bytes -= old.len;
+            if let Some(new) = new {
+                writer.stats.history_bytes += new.len;
+                writer.stats.keep(&new);
+            }
+            self.superseded.insert(entry.key().clone(), new);
+        }
+
+        for (key, old) in &compaction.expired {
+            if self.index.get(key).map(|e| *e.value()) == Some(*old) {
+                self.index.remove(key);
+                writer.stats.live_bytes -= old.len;
+                writer.stats.expiries.remove(&(old.expires.unwrap(), key.clone()));
+            }
+        }
+
+        for id in &plan.sealed {
+            writer.stats.segment_lens.remove(id);
+            writer.stats.segments.remove(id);
+            self.segments.remove(id);
+        }
+
+        Ok(())
+    }
+
+    fn remove_sealed(&self, sealed: &[SegmentId]) -> Result<()> {
+        for id in sealed {
+            // The hint goes first, thus there never is a hint without its
+            // segment.
+            let hint = hint_path(&self.path, *id);
+            match std::fs::remove_file(&hint) {
+                Err(ref c) if c.kind() == std::io::ErrorKind::NotFound => {}
+                res => res.map_err(|c| KvStoreError::FileRemoveFailure {
+                    c,
+                    name: hint.display().to_string(),
+                })?,
+            }
+
+            let path = segment_path(&self.path, *id);
+            std::fs::remove_file(&path).map_err(|c| KvStoreError::FileRemoveFailure {
+                c,
+                name: path.display().to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Points the index to the record at the given position, keeping the version
+/// it supersedes in `superseded` if given. The record either sets the key or,
+/// without `set`, removes it.
+fn apply(
+    index: &SkipMap<Vec<u8>, Position>,
+    superseded: Option<&Superseded>,
+    stats: &mut LogStats,
+    key: Vec<u8>,
+    position: Position,
+    set: bool,
+) {
+    stats.add_record(&key, &position);
+
+    let old = index.get(&key).map(|e| *e.value());
+
+    // Whether the version superseded by the write is kept.
+    let mut kept = false;
+    if let Some(superseded) = superseded {
+        // The first version superseded by a write is the one seen by
+        // snapshots, a batch might write a key more than once.
+        let version = (key.clone(), position.seq);
+        if !superseded.contains_key(&version) {
+            stats.history_bytes += old.map_or(0, |old| old.len);
+            stats.versions.insert((position.seq, key.clone()));
+            superseded.insert(version, old);
+            kept = true;
+        }
+    }
+
+    if let Some(expires) = old.and_then(|old| old.expires) {
+        stats.expiries.remove(&(expires, key.clone()));
+    }
+
+    if set {
+        stats.live_bytes += position.len;
+        stats.keep(&position);
+        if let Some(expires) = position.expires {
+            stats.expiries.insert((expires, key.clone()));
+        }
+        index.insert(key, position);
+    } else {
+        index.remove(&key);
+    }
+
+    if let Some(old) = old {
+        stats.live_bytes -= old.len;
+        if !kept {
+            stats.release(&old);
+        }
+    }
+}
+
+/// Replay holds the state rebuilt by replaying the log.
+struct Replay<'a> {
+    index: &'a SkipMap<Vec<u8>, Position>,
+    superseded: &'a Superseded,
+    // Whether to keep superseded versions.
+    history: bool,
+    // Whether to cut off a torn write at the end of the log.
+    truncate: bool,
+    stats: &'a mut LogStats,
+    // Sequence number of the last replayed write.
+    seq: &'a mut u64,
+    // Sequence number of the last removal of each absent key.
+    removed: HashMap<Vec<u8>, u64>,
+}
+
+impl Replay<'_> {
+    /// Returns the sequence number of a replayed write given the one of its
+    /// record, counting on from the last one for records of earlier versions
+    /// without a sequence number.
+    fn next_seq(&mut self, recorded: u64) -> u64 {
+        if recorded == 0 {
+            *self.seq += 1;
+            return *self.seq;
+        }
+
+        // Compacted segments hold records of older writes.
+        *self.seq = std::cmp::max(*self.seq, recorded);
+        recorded
+    }
+
+    fn apply(&mut self, key: Vec<u8>, position: Position, set: bool) {
+        // Compacted segments follow segments holding newer writes of the same
+        // keys, the older write is at most a superseded version.
+        let latest = match self.index.get(&key) {
+            Some(entry) => Some(entry.value().seq),
+            None => self.removed.get(&key).cloned(),
+        };
+        if latest.is_some_and(|latest| position.seq < latest) {
+            self.stats.add_record(&key, &position);
+            if self.history {
+                insert_version(self.superseded, self.stats, key, position, set);
+            }
+            return;
+        }
+
+        if set {
+            self.removed.remove(&key);
+        } else {
+            self.removed.insert(key.clone(), position.seq);
+        }
+
+        let superseded = if self.history { Some(self.superseded) } else { None };
+        apply(self.index, superseded, self.stats, key, position, set);
+    }
+}
+
+/// Inserts the version written by a write older than the latest one of its
+/// key into `superseded`, unless it is there already.
+fn insert_version(superseded: &Superseded, stats: &mut LogStats, key: Vec<u8>, position: Position, set: bool) {
+    let version = (key, position.seq);
+    if superseded.contains_key(&version) {
+        return;
+    }
+
+    // The write following this one supersedes its version instead of the one
+    // preceding it.
+    let next = match superseded.lower_bound(Bound::Excluded(&version)) {
+        Some(entry) if entry.key().0 == version.0 => entry,
+        _ => return,
+    };
+    let preceding = *next.value();
+    let next = next.key().clone();
+
+    stats.versions.insert((version.1, version.0.clone()));
+    superseded.insert(version, preceding);
+    if set {
+        stats.history_bytes += position.len;
+        stats.keep(&position);
+        superseded.insert(next, Some(position));
+    } else {
+        superseded.insert(next, None);
+    }
+}
+
+/// Replays the records appended to the log since the given segments were
+/// replayed, for a log opened read-only following a writer.
+///
+/// Compacted segments are skipped until they have a hint file, i.e. are
+/// complete. Their records are still part of the segments being compacted.
+/// Returns the ids of the segments skipped.
+fn follow_segments(
+    dir: &Path,
+    segments: &SkipMap<SegmentId, Arc<SegmentFile>>,
+    replay: &mut Replay,
+    ids: &[SegmentId],
+) -> Result<Vec<SegmentId>> {
+    let tail = segments.back().map(|entry| *entry.key());
+    let mut skipped = Vec::new();
+
+    for id in ids {
+        let start = match tail {
+            // Segments before the last one replayed are either replayed
+            // already or compacted segments skipped.
+            Some(tail) if *id < tail => continue,
+            Some(tail) if *id == tail => replay.stats.segment_lens.get(id).cloned().unwrap_or(0),
+            _ => {
+                let segment = SegmentFile::open(dir, *id)?;
+                let compacted = match segment.is_compacted(*id) {
+                    Ok(compacted) => compacted,
+                    Err(KvStoreError::TornWrite { .. }) => true,
+                    Err(e) => return Err(e),
+                };
+                if compacted && !hint_path(dir, *id).exists() {
+                    skipped.push(*id);
+                    continue;
+                }
+                segments.insert(*id, Arc::new(segment));
+                0
+            }
+        };
+
+        if start == 0 && replay_hint(dir, replay, *id)? {
+            continue;
+        }
+
+        // The writer might be appending to any of the segments replayed.
+        replay_segment(dir, segments, replay, *id, true, start)?;
+    }
+
+    Ok(skipped)
+}
+
+/// Replays the given segment from its hint file instead of reading its
+/// records, returning whether there is a hint file to do so. Only compacted
+/// segments have one.
+///
+/// A hint file failing its checks or not matching its segment is skipped,
+/// leaving the segment to be replayed.
+fn replay_hint(dir: &Path, replay: &mut Replay, id: SegmentId) -> Result<bool> {
+    let hint = match hint::read(&hint_path(dir, id), id) {
+        Ok(Some(hint)) => hint,
+        Ok(None) => return Ok(false),
+        Err(e @ KvStoreError::CorruptHint { .. }) => {
+            warn!("{}, replaying the segment instead", e);
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let path = segment_path(dir, id);
+    let len = std::fs::metadata(&path)
+        .map_err(|c| KvStoreError::ReadFromFileFailure { c })?
+        .len();
+    if hint.len != len {
+        warn!(
+            "hint file of log segment {} does not match its length, replaying the segment instead",
+            id
+        );
+        return Ok(false);
+    }
+
+    replay.stats.segment_lens.insert(id, len);
+
+    for entry in hint.entries {
+        let position = Position {
+            segment: id,
+            offset: entry.offset,
+            len: entry.len,
+            seq: 0,
+            expires: entry.expires,
+        };
+
+        match entry.kind {
+            HintKind::Sequence => {
+                replay.next_seq(entry.seq);
+            }
+            kind => {
+                let seq = replay.next_seq(entry.seq);
+                replay.apply(entry.key, Position { seq, ..position }, kind == HintKind::Set);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Replays the records of the given segment into the index, starting at
+/// offset `start` if not 0. Only segments in the current format are ever
+/// appended to, thus continued.
+///
+/// A torn write at the end of the last segment, left behind by a process
+/// dying half way through a write, is cut off.
+fn replay_segment(
+    dir: &Path,
+    segments: &SkipMap<SegmentId, Arc<SegmentFile>>,
+    replay: &mut Replay,
+    id: SegmentId,
+    last: bool,
+    start: Offset,
+) -> Result<()> {
+    let path = segment_path(dir, id);
+    let file = open_segment(dir, id)?;
+    let len = file
+        .metadata()
+        .map_err(|c| KvStoreError::ReadFromFileFailure { c })?
+        .len();
+    let mut reader = std::io::BufReader::new(file);
+
+    if start > 0 && start >= len {
+        return Ok(());
+    }
+
+    replay.stats.segment_lens.insert(id, len);
+
+    let version = segments.get(&id).unwrap().value().version;
+    if version == record::LEGACY_FORMAT_VERSION {
+        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+
+        let mut offset: Offset = 0;
+        while let Some(cmd) = stream.next() {
+            let cmd: Command = cmd.map_err(|c| KvStoreError::DeserializationFailure { c })?.into();
+
+            let end = stream.byte_offset() as Offset;
+            let position = Position {
+                segment: id,
+                offset,
+                len: end - offset,
+                seq: replay.next_seq(0),
+                expires: cmd.expires(),
+            };
+            replay.apply(cmd.key(), position, cmd.is_set());
+
+            offset = end;
+        }
+
+        return Ok(());
+    }
+
+    let records = if start > 0 {
+        reader
+            .seek(std::io::SeekFrom::Start(start))
+            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+        RecordReader::resume(reader, id, start, len, last)
+    } else {
+        reader
+            .seek(std::io::SeekFrom::Start(std::cmp::min(len, record::SEGMENT_HEADER_LEN)))
+            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+        RecordReader::new(reader, id, len, last)
+    };
+
+    // Commands of a batch are only applied once the whole batch is read.
+    let mut batch: Option<PendingBatch> = None;
+    let mut torn = None;
+
+    for record in records {
+        let record = match record {
+            Ok(record) => record,
+            Err(KvStoreError::TornWrite { offset, .. }) => {
+                torn = Some(offset);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let Record { seq, cmd, .. } = Record::decode(id, record.offset, version, &record.payload)?;
+
+        // The sequence number is assigned once the write is read completely.
+        let position = Position {
+            segment: id,
+            offset: record.offset,
+            len: record.len,
+            seq: 0,
+            expires: cmd.expires(),
+        };
+
+        match (cmd, &mut batch) {
+            (Command::Batch { .. }, Some(_)) | (Command::Sequence, Some(_)) => {
+                return Err(KvStoreError::CorruptRecord {
+                    segment: id,
+                    offset: record.offset,
+                })
+            }
+            (Command::Batch { count }, None) => {
+                batch = Some(PendingBatch {
+                    offset: record.offset,
+                    count,
+                    seq,
+                    cmds: Vec::new(),
+                });
+            }
+            (Command::Sequence, None) => {
+                replay.next_seq(seq);
+            }
+            (cmd, Some(pending)) => {
+                pending.cmds.push((cmd, position));
+                if pending.cmds.len() as u64 == pending.count {
+                    let pending = batch.take().unwrap();
+                    let seq = replay.next_seq(pending.seq);
+                    for (cmd, position) in pending.cmds {
+                        replay.apply(cmd.key(), Position { seq, ..position }, cmd.is_set());
+                    }
+                }
+            }
+            (cmd, None) => {
+                let seq = replay.next_seq(seq);
+                replay.apply(cmd.key(), Position { seq, ..position }, cmd.is_set());
+            }
+        }
+    }
+
+    // A batch cut short is the result of a torn write as well, given that
+    // batches never span multiple segments.
+    if let Some(pending) = batch {
+        if !last {
+            return Err(KvStoreError::CorruptRecord {
+                segment: id,
+                offset: pending.offset,
+            });
+        }
+
+        torn = Some(pending.offset);
+    }
+
+    if let Some(offset) = torn {
+        if replay.truncate {
+            warn!(
+                "truncating torn write in log segment {} at offset {}, dropping {} bytes",
+                id,
+                offset,
+                len - offset
+            );
+            truncate_segment(&path, offset)?;
+        }
+        replay.stats.segment_lens.insert(id, offset);
+    }
+
+    Ok(())
+}
+
+/// Batch read while replaying the log, not yet applied.
+struct PendingBatch {
+    // Offset of the batch marker.
+    offset: Offset,
+    // Number of commands of the batch.
+    count: u64,
+    // Sequence number of the batch marker.
+    seq: u64,
+    cmds: Vec<(Command, Position)>,
+}
+
+/// Serializes and frames the given command of the write with the given
+/// sequence number and time.
+fn encode(seq: u64, time: u64, cmd: &Command) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(&Record { seq, time, cmd })
+        .map_err(|c| KvStoreError::BinarySerializationFailure { c })?;
+
+    Ok(record::encode(&payload))
+}
+
+fn truncate_segment(path: &Path, len: u64) -> Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|c| KvStoreError::OpenFileFailure {
+            c,
+            name: path.display().to_string(),
+        })?;
+
+    file.set_len(len)
+        .and_then(|()| file.sync_all())
+        .map_err(|c| KvStoreError::WriteToFileFailure { c })
+}
+
+/// CompactionPlan describes a single compaction run.
+struct CompactionPlan {
+    // Segments to be compacted, in ascending order.
+    sealed: Vec<SegmentId>,
+    // Sequence number of the last write before compaction started.
+    seq: u64,
+    // Whether the compacted segments carry the sequence number over, as no
+    // other segment holds the last write.
+    carry_seq: bool,
+    // Range of segment ids reserved for the compacted segments.
+    first_output: SegmentId,
+    last_output: SegmentId,
+    // Id, lowest sequence number and key hashes of the segments left alone.
+    others: Vec<(SegmentId, u64, Arc<HashSet<u64>>)>,
+}
+
+impl CompactionPlan {
+    /// Returns the segments left alone that might hold records of the given
+    /// key older than the write with the given sequence number.
+    fn shadowed(&self, key: &[u8], seq: u64) -> Vec<SegmentId> {
+        let hash = key_hash(key);
+
+        self.others
+            .iter()
+            .filter(|(_, min_seq, keys)| *min_seq < seq && keys.contains(&hash))
+            .map(|(id, _, _)| *id)
+            .collect()
+    }
+}
+
+/// Compaction holds the outcome of copying the records kept by a compaction
+/// run.
+struct Compaction {
+    // Key along with the old and the new position of each copied record.
+    moved: Vec<(Vec<u8>, Position, Position)>,
+    // Expired records dropped by compaction.
+    expired: Vec<(Vec<u8>, Position)>,
+    // Compacted segments, their stats lacking the copied records.
+    outputs: Vec<(SegmentId, SegmentStats)>,
+}
+
+/// CompactionOutput writes the records kept by compaction to new segments.
+///
+/// Each compacted segment starts with a marker carrying the sequence number of
+/// the last write before compaction started over.
+struct CompactionOutput<'a> {
+    dir: &'a Path,
+    max_segment_size: u64,
+    seq: u64,
+    // Id of the next segment to write to.
+    next: SegmentId,
+    last_output: SegmentId,
+    writer: Option<LogWriter>,
+    // Hint entries of the segment written to.
+    hint: Vec<HintEntry>,
+    stats: SegmentStats,
+    // Segments written already.
+    sealed: Vec<(SegmentId, SegmentStats)>,
+}
+
+impl CompactionOutput<'_> {
+    /// Writes a record of the given key, moving on to the next segment once
+    /// the current one is full.
+    fn write(&mut self, key: &[u8], record: &[u8], seq: u64, expires: Option<u64>, kind: HintKind) -> Result<Position> {
+        let full = match &self.writer {
+            Some(writer) => {
+                writer.position + record.len() as u64 > self.max_segment_size && writer.segment < self.last_output
+            }
+            None => true,
+        };
+        if full {
+            self.start()?;
+        }
+
+        self.append(key, record, seq, expires, kind)
+    }
+
+    fn write_tombstone(
+        &mut self,
+        key: &[u8],
+        record: &[u8],
+        seq: u64,
+        history: bool,
+        shadowed: Vec<SegmentId>,
+    ) -> Result<()> {
+        let written = self.write(key, record, seq, None, HintKind::Remove)?;
+
+        self.stats.kept_bytes += written.len;
+        self.stats.tombstones.push(KeptTombstone {
+            len: written.len,
+            seq,
+            history,
+            shadowed,
+        });
+
+        Ok(())
+    }
+
+    /// Seals the segment written to, if any, and starts the next one.
+    fn start(&mut self) -> Result<()> {
+        if self.writer.is_some() {
+            self.seal()?;
+        }
+
+        self.writer = Some(LogWriter::open(self.dir, self.next)?);
+        self.next += 1;
+
+        let marker = encode(self.seq, now_millis(), &Command::Sequence)?;
+        self.append(&[], &marker, self.seq, None, HintKind::Sequence)?;
+
+        Ok(())
+    }
+
+    fn append(&mut self, key: &[u8], record: &[u8], seq: u64, expires: Option<u64>, kind: HintKind) -> Result<Position> {
+        let written = match &mut self.writer {
+            Some(writer) => writer.write(record)?,
+            None => unreachable!("records are only appended to a started segment"),
+        };
+
+        self.hint.push(HintEntry {
+            key: key.to_vec(),
+            offset: written.offset,
+            len: written.len,
+            seq,
+            expires,
+            kind,
+        });
+        if kind != HintKind::Sequence {
+            self.stats.add_record(key, seq);
+        }
+
+        Ok(Position { seq, expires, ..written })
+    }
+
+    /// Syncs the segment written to, then writes its hint file.
+    fn seal(&mut self) -> Result<()> {
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.sync()?;
+
+        let hint = Hint {
+            len: writer.position,
+            entries: std::mem::take(&mut self.hint),
+        };
+        hint::write(&hint_path(self.dir, writer.segment), &hint)?;
+
+        let stats = std::mem::take(&mut self.stats);
+        self.sealed.push((writer.segment, stats));
+
+        Ok(())
+    }
+
+    /// Seals the last segment, returning all segments written.
+    fn finish(mut self) -> Result<Vec<(SegmentId, SegmentStats)>> {
+        self.seal()?;
+
+        Ok(self.sealed)
+    }
+}
+
+/// SegmentFile gives read access to a single log segment.
+///
+/// Records are read through positional reads, which do not move a shared
+/// cursor, thereby allowing any number of concurrent readers.
+struct SegmentFile {
+    file: std::fs::File,
+    // On-disk format version of the segment.
+    version: u32,
+}
+
+impl SegmentFile {
+    fn open(dir: &Path, id: SegmentId) -> Result<SegmentFile> {
+        // Segments are created and appended to by `LogWriter`.
+        let file = open_segment(dir, id)?;
+
+        let mut header = vec![0; record::SEGMENT_HEADER_LEN as usize];
+        let len = file
+            .metadata()
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })?
+            .len();
+        header.truncate(std::cmp::min(len, record::SEGMENT_HEADER_LEN) as usize);
+        read_exact_at(&file, &mut header, 0)
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })?;
+
+        let version = record::segment_version(id, &header)?;
+
+        Ok(SegmentFile { file, version })
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.file
+            .metadata()
+            .map(|m| m.len())
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })
+    }
+
+    /// Returns whether the segment with the given id was written by
+    /// compaction, which starts each run with a sequence marker. Fails with
+    /// `TornWrite` while the first record is being written.
+    fn is_compacted(&self, id: SegmentId) -> Result<bool> {
+        if self.version != record::FORMAT_VERSION {
+            return Ok(false);
+        }
+
+        let len = self.len()?;
+        let mut file = &self.file;
+        file.seek(std::io::SeekFrom::Start(std::cmp::min(len, record::SEGMENT_HEADER_LEN)))
+            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+
+        match RecordReader::new(std::io::BufReader::new(file), id, len, true).next() {
+            Some(record) => {
+                let record = record?;
+                let record = Record::decode(id, record.offset, self.version, &record.payload)?;
+                Ok(matches!(record.cmd, Command::Sequence))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns whether the last record of the segment is cut short, as left
+    /// behind by the process dying half way through writing the segment.
+    fn is_torn(&self, id: SegmentId) -> Result<bool> {
+        let len = self.len()?;
+        let mut file = &self.file;
+        file.seek(std::io::SeekFrom::Start(std::cmp::min(len, record::SEGMENT_HEADER_LEN)))
+            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+
+        for record in RecordReader::new(std::io::BufReader::new(file), id, len, true) {
+            match record {
+                Ok(_) => {}
+                Err(KvStoreError::TornWrite { .. }) => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Returns all records of the segment along with their offset, in order.
+    /// Records of earlier versions carry no sequence number.
+    fn read_records(&self, id: SegmentId) -> Result<Vec<(Offset, Record<Command>)>> {
+        let len = self.len()?;
+        let mut data = vec![0; len as usize];
+        read_exact_at(&self.file, &mut data, 0).map_err(|c| KvStoreError::ReadFromFileFailure { c })?;
+
+        let mut records = Vec::new();
+
+        if self.version == record::LEGACY_FORMAT_VERSION {
+            let mut stream = serde_json::Deserializer::from_slice(&data).into_iter::<JsonCommand>();
+
+            let mut offset: Offset = 0;
+            while let Some(cmd) = stream.next() {
+                let cmd = cmd.map_err(|c| KvStoreError::DeserializationFailure { c })?.into();
+                records.push((offset, Record { seq: 0, time: 0, cmd }));
+                offset = stream.byte_offset() as Offset;
+            }
+
+            return Ok(records);
+        }
+
+        let start = std::cmp::min(len, record::SEGMENT_HEADER_LEN) as usize;
+        for record in RecordReader::new(&data[start..], id, len, false) {
+            let record = record?;
+            records.push((record.offset, Record::decode(id, record.offset, self.version, &record.payload)?));
+        }
+
+        Ok(records)
+    }
+
+    /// Returns the command of the record at the given position, verifying
+    /// its checksum.
+    fn read_command(&self, position: Position) -> Result<Command> {
+        self.read_record(position).map(|record| record.cmd)
+    }
+
+    /// Returns the record at the given position, verifying its checksum.
+    fn read_record(&self, position: Position) -> Result<Record<Command>> {
+        let mut record = vec![0; position.len as usize];
+
+        read_exact_at(&self.file, &mut record, position.offset)
+            .map_err(|c| KvStoreError::ReadFromFileFailure { c })?;
+
+        let payload = if self.version == record::LEGACY_FORMAT_VERSION {
+            &record[..]
+        } else {
+            record::decode(position.segment, position.offset, &record)?
+        };
+
+        Record::decode(position.segment, position.offset, self.version, payload)
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: Offset) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: Offset) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// LogWriter appends records to a single log segment.
+struct LogWriter {
+    segment: SegmentId,
+    file: std::fs::File,
+    // Position within the file.
+    position: Offset,
+    // Bytes written since the last sync.
+    unsynced_bytes: u64,
+    synced_at: Instant,
+}
+
+impl LogWriter {
+    fn open(dir: &Path, segment: SegmentId) -> Result<LogWriter> {
+        let path = segment_path(dir, segment);
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|c| KvStoreError::OpenFileFailure {
+                c,
+                name: path.display().to_string(),
+            })?;
+
+        // Get end of file.
+        let position = file
+            .seek(std::io::SeekFrom::End(0))
+            .map_err(|c| KvStoreError::SeekFileFailure { c })?;
+
+        let mut writer = LogWriter {
+            segment,
+            file,
+            position,
+            unsynced_bytes: 0,
+            synced_at: Instant::now(),
+        };
+
+        if position == 0 {
+            writer.write(&record::segment_header())?;
+        }
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, record: &[u8]) -> Result<Position> {
+        let offset = self.position;
+
+        self.file
+            .write_all(record)
+            .map_err(|c| KvStoreError::WriteToFileFailure { c })?;
+
+        self.position += record.len() as u64;
+        self.unsynced_bytes += record.len() as u64;
+
+        Ok(Position {
+            segment: self.segment,
+            offset,
+            len: record.len() as u64,
+            seq: 0,
+            expires: None,
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .map_err(|c| KvStoreError::FileFlushFailure { c })?;
+
+        self.unsynced_bytes = 0;
+        self.synced_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, id: SegmentId) -> PathBuf {
+    dir.join(format!("{}.log", id))
+}
+
+/// Returns the path of the hint file of a compacted segment.
+fn hint_path(dir: &Path, id: SegmentId) -> PathBuf {
+    dir.join(format!("{}.hint", id))
+}
+
+/// Returns the ids of all log segments within `dir` in ascending order.
+///
+/// The log file written by earlier versions counts as the first segment until
+/// a writable store adopts it, thus a store opened read-only replays it in
+/// place.
+fn segment_ids(dir: &Path) -> Result<Vec<SegmentId>> {
+    let ids = file_ids(dir, "log")?;
+    if ids.is_empty() && dir.join(LEGACY_LOG_FILE).is_file() {
+        return Ok(vec![0]);
+    }
+
+    Ok(ids)
+}
+
+/// Opens the log segment with the given id for reading, falling back to the
+/// log file written by earlier versions for the first segment.
+fn open_segment(dir: &Path, id: SegmentId) -> Result<std::fs::File> {
+    let path = segment_path(dir, id);
+    match std::fs::File::open(&path) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound && id == 0 => {
+            let legacy = dir.join(LEGACY_LOG_FILE);
+            std::fs::File::open(&legacy).map_err(|c| KvStoreError::OpenFileFailure {
+                c,
+                name: legacy.display().to_string(),
+            })
+        }
+        file => file.map_err(|c| KvStoreError::OpenFileFailure {
+            c,
+            name: path.display().to_string(),
+        }),
+    }
+}
+
+/// Returns the ids of all files named `{id}.{extension}` within `dir` in
+/// ascending order.
+fn file_ids(dir: &Path, extension: &str) -> Result<Vec<SegmentId>> {
+    let entries = std::fs::read_dir(dir).map_err(|c| KvStoreError::ReadDirFailure {
+        c,
+        name: dir.display().to_string(),
+    })?;
+
+    let mut ids = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|c| KvStoreError::ReadDirFailure {
+            c,
+            name: dir.display().to_string(),
+        })?;
+
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(extension) {
+            continue;
+        }
+
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<SegmentId>().ok())
+        {
+            ids.push(id);
+        }
+    }
+
+    ids.sort();
+
+    Ok(ids)
+}
+
+/// Returns whether the given directory holds a store, including one written
+/// by earlier versions.
+fn store_exists(dir: &Path) -> Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+
+    Ok(crate::manifest::is_claimed(dir) || dir.join(LEGACY_LOG_FILE).is_file() || !segment_ids(dir)?.is_empty())
+}
+
+/// Turns the single log file written by earlier versions into the first
+/// segment.
+fn adopt_legacy_log(dir: &Path) -> Result<()> {
+    let legacy = dir.join(LEGACY_LOG_FILE);
+
+    if !legacy.is_file() || !file_ids(dir, "log")?.is_empty() {
+        return Ok(());
+    }
+
+    std::fs::rename(&legacy, segment_path(dir, 0))
+        .map_err(|c| KvStoreError::FileMoveFailure { c })
+}
+
+/// Removes the segments left behind by a compaction run the process died in.
+///
+/// Those were written by compaction, lack a hint file and end in a torn
+/// record, as their hint file is only written once they are complete. Their
+/// records are still part of the segments being compacted, which are only
+/// removed after all compacted segments got their hint file. A complete
+/// compacted segment is kept, replaying it along with the segments it
+/// compacted does not change the outcome. The last segment is the active
+/// segment, and never written by compaction.
+fn remove_incomplete_outputs(dir: &Path) -> Result<()> {
+    let ids = segment_ids(dir)?;
+
+    for id in ids.iter().rev().skip(1) {
+        if hint_path(dir, *id).exists() {
+            continue;
+        }
+
+        let segment = SegmentFile::open(dir, *id)?;
+        let incomplete = match segment.is_compacted(*id) {
+            // The segment header is yet to be written.
+            Ok(_) if segment.len()? == 0 => true,
+            Ok(compacted) => compacted && segment.is_torn(*id)?,
+            // The first record, or the segment header, is torn.
+            Err(KvStoreError::TornWrite { .. }) => true,
+            Err(e) => return Err(e),
+        };
+        if !incomplete {
+            continue;
+        }
+        drop(segment);
+
+        warn!("removing log segment {} left behind by an unfinished compaction", id);
+        let path = segment_path(dir, *id);
+        std::fs::remove_file(&path).map_err(|c| KvStoreError::FileRemoveFailure {
+            c,
+            name: path.display().to_string(),
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Record as written to the log, encoded with bincode.
+#[derive(Serialize, Deserialize)]
+struct Record<C> {
+    /// Sequence number of the write.
+    seq: u64,
+    /// Time of the write in milliseconds since the unix epoch.
+    time: u64,
+    cmd: C,
+}
+
+impl Record<Command> {
+    /// Decodes the payload of a record of a segment with the given format
+    /// version. Records of earlier versions carry neither a sequence number
+    /// nor a time, both are 0 for those.
+    fn decode(segment: SegmentId, offset: Offset, version: u32, payload: &[u8]) -> Result<Record<Command>> {
+        let unnumbered = |cmd| Record { seq: 0, time: 0, cmd };
+
+        if version <= record::JSON_FORMAT_VERSION {
+            let cmd: JsonCommand = serde_json::from_slice(payload)
+                .map_err(|c| KvStoreError::DeserializationFailure { c })?;
+            return Ok(unnumbered(cmd.into()));
+        }
+
+        // The payload passed its checksum, thus failing to decode it is
+        // corruption within the record.
+        let corrupt = |_| KvStoreError::CorruptRecord { segment, offset };
+
+        if version == record::BINARY_FORMAT_VERSION {
+            return bincode::deserialize(payload).map(unnumbered).map_err(corrupt);
+        }
+
+        bincode::deserialize(payload).map_err(corrupt)
+    }
+}
+
+/// Command as written to the log.
+#[derive(Serialize, Deserialize)]
+enum Command {
+    Set {
+        k: Vec<u8>,
+        v: Vec<u8>,
+        /// Expiry in milliseconds since the unix epoch.
+        e: Option<u64>,
+    },
+    Remove { k: Vec<u8> },
+    /// Marks the start of `count` commands applied atomically.
+    Batch { count: u64 },
+    /// Carries the sequence number of the last write over to compacted
+    /// segments.
+    Sequence,
+}
+
+impl Command {
+    fn key(&self) -> Vec<u8> {
+        match self {
+            Command::Set { k, .. } => k.clone(),
+            Command::Remove { k } => k.clone(),
+            Command::Batch { .. } | Command::Sequence => unreachable!("markers are never indexed"),
+        }
+    }
+
+    /// Returns whether the command sets its key, rather than removing it.
+    fn is_set(&self) -> bool {
+        match self {
+            Command::Set { .. } => true,
+            Command::Remove { .. } => false,
+            Command::Batch { .. } | Command::Sequence => unreachable!("markers are never indexed"),
+        }
+    }
+
+    fn expires(&self) -> Option<u64> {
+        match self {
+            Command::Set { e, .. } => *e,
+            Command::Remove { .. } | Command::Batch { .. } | Command::Sequence => None,
+        }
+    }
+
+    fn into_value(self) -> Option<Vec<u8>> {
+        match self {
+            Command::Set { v, .. } => Some(v),
+            Command::Remove { .. } | Command::Batch { .. } | Command::Sequence => None,
+        }
+    }
+}
+
+/// Command as written by earlier versions, encoded as JSON and limited to
+/// utf8 keys and values.
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set {
+        k: String,
+        v: String,
+        #[serde(default)]
+        e: Option<u64>,
+    },
+    Remove { k: String },
+    Batch { count: u64 },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { k, v, e } => Command::Set {
+                k: k.into_bytes(),
+                v: v.into_bytes(),
+                e,
+            },
+            JsonCommand::Remove { k } => Command::Remove { k: k.into_bytes() },
+            JsonCommand::Batch { count } => Command::Batch { count },
+        }
+    }
+}
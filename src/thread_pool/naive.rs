@@ -8,7 +8,7 @@ pub struct NaiveThreadPool {}
 
 impl ThreadPool for NaiveThreadPool {
     /// Return new thread pool.
-    fn new(threads: u32) -> Result<Self>
+    fn new(_threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
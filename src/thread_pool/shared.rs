@@ -1,6 +1,6 @@
 use log::error;
 use std::panic;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -10,11 +10,10 @@ use crate::error::Result;
 /// A shared thread pool implementation.
 pub struct SharedQueueThreadPool {
     tx: Sender<Job>,
-    rx: Arc<Mutex<Receiver<Job>>>,
     handles: Vec<thread::JoinHandle<()>>,
 }
 
-type Job = Box<FnOnce() + Send + 'static + std::panic::UnwindSafe>;
+type Job = Box<dyn FnOnce() + Send + 'static + std::panic::UnwindSafe>;
 
 impl ThreadPool for SharedQueueThreadPool {
     /// Return new thread pool.
@@ -43,7 +42,7 @@ impl ThreadPool for SharedQueueThreadPool {
                             Ok(()) => {}
                             Err(e) => error!("{:?}", e),
                         },
-                        Err(e) => {
+                        Err(_) => {
                             // Sender was dropped, thereby closing the thread.
                             return;
                         }
@@ -52,7 +51,7 @@ impl ThreadPool for SharedQueueThreadPool {
             }));
         }
 
-        Ok(SharedQueueThreadPool { tx, rx, handles })
+        Ok(SharedQueueThreadPool { tx, handles })
     }
 
     /// Spawn the given job on the thread pool.
@@ -60,7 +59,9 @@ impl ThreadPool for SharedQueueThreadPool {
     where
         F: FnOnce() + Send + 'static + std::panic::UnwindSafe,
     {
-        self.tx.send(Box::new(job));
+        if let Err(e) = self.tx.send(Box::new(job)) {
+            error!("failed to spawn job: {:?}", e);
+        }
     }
 }
 
@@ -0,0 +1,96 @@
+use crate::batch::WriteBatch;
+use crate::error::{KvStoreError, Result};
+use crate::store::Snapshot;
+use std::collections::{BTreeMap, HashMap};
+
+/// Transaction buffers the reads and writes of a single run of
+/// `KvStore::transaction`.
+///
+/// Reads see the store as of the start of the transaction, plus the writes of
+/// the transaction itself.
+pub struct Transaction {
+    snapshot: Snapshot,
+    // Values read from the snapshot, expected to be unchanged at commit.
+    reads: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    // Buffered writes, `None` standing for a removal.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(snapshot: Snapshot) -> Transaction {
+        Transaction {
+            snapshot,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the value for the given key.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(v) = self.writes.get(&key) {
+            return Ok(v.clone());
+        }
+
+        if let Some(v) = self.reads.get(&key) {
+            return Ok(v.clone());
+        }
+
+        let v = self.snapshot.get_bytes(key.clone())?;
+        self.reads.insert(key, v.clone());
+
+        Ok(v)
+    }
+
+    /// Returns the value for the given key, failing on a value that is not
+    /// valid utf8.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())?
+            .map(|v| String::from_utf8(v).map_err(|c| KvStoreError::Utf8Failure { c }))
+            .transpose()
+    }
+
+    /// Sets the value for the given key.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Sets the value for the given key.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes the value of the given key, failing with
+    /// `KvStoreError::KeyNotFound` if it does not exist.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvStoreError::KeyNotFound);
+        }
+
+        self.writes.insert(key, None);
+
+        Ok(())
+    }
+
+    /// Removes the value of the given key, failing with
+    /// `KvStoreError::KeyNotFound` if it does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Returns the batch committing the transaction.
+    pub(crate) fn into_batch(self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+
+        for (key, v) in self.reads {
+            batch.expect(key, v);
+        }
+        for (key, v) in self.writes {
+            match v {
+                Some(v) => batch.set(key, v),
+                None => batch.remove(key),
+            }
+        }
+
+        batch
+    }
+}
@@ -0,0 +1,168 @@
+use crate::error::{KvStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+/// Number of writes buffered for a watch before it counts as fallen behind.
+const WATCH_CAPACITY: usize = 1024;
+
+/// WatchEvent is a committed write to a watched key, as returned by
+/// `KvsEngine::watch`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The key was set to the value.
+    Set {
+        /// Key written.
+        key: Vec<u8>,
+        /// Value written.
+        value: Vec<u8>,
+        /// Sequence number of the write, if the engine assigns them.
+        seq: Option<u64>,
+        /// Time of the write in milliseconds since the unix epoch, if the
+        /// engine records it.
+        time: Option<u64>,
+    },
+    /// The key was removed.
+    Remove {
+        /// Key removed.
+        key: Vec<u8>,
+        /// Sequence number of the write, if the engine assigns them.
+        seq: Option<u64>,
+        /// Time of the write in milliseconds since the unix epoch, if the
+        /// engine records it.
+        time: Option<u64>,
+    },
+}
+
+/// Watch is an iterator over the writes to watched keys in commit order, as
+/// returned by `KvsEngine::watch`. It blocks until the next write and ends
+/// once the engine is dropped.
+///
+/// Writes are buffered until read. A watch falling too far behind ends with
+/// `KvStoreError::WatchLagged`, and dropping a watch ends it right away.
+pub struct Watch {
+    events: Receiver<WatchEvent>,
+    // Set once the watch is dropped for falling behind.
+    lagged: Arc<AtomicBool>,
+    // Watches of the engine, to drop this one from once dropped.
+    watchers: Weak<Mutex<Vec<Watcher>>>,
+    ended: bool,
+}
+
+impl Watch {
+    /// Waits at most `timeout` for the next write, failing with
+    /// `RecvTimeoutError::Timeout` if there is none, and with
+    /// `RecvTimeoutError::Disconnected` once the watch ended.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> std::result::Result<Result<WatchEvent>, RecvTimeoutError> {
+        if self.ended {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Ok(Ok(event)),
+            Err(RecvTimeoutError::Timeout) => Err(RecvTimeoutError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => self.end().ok_or(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Ends the watch once all buffered writes are read, reporting whether it
+    /// fell behind.
+    fn end(&mut self) -> Option<Result<WatchEvent>> {
+        self.ended = true;
+
+        if self.lagged.load(Ordering::SeqCst) {
+            Some(Err(KvStoreError::WatchLagged))
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended {
+            return None;
+        }
+
+        match self.events.recv() {
+            Ok(event) => Some(Ok(event)),
+            Err(_) => self.end(),
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(watchers) = self.watchers.upgrade() {
+            watchers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|watcher| !Arc::ptr_eq(&watcher.lagged, &self.lagged));
+        }
+    }
+}
+
+/// Watcher is the sending end of a single watch.
+struct Watcher {
+    prefix: Vec<u8>,
+    tx: SyncSender<WatchEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Watchers keeps track of the watches of a store, handing each of them the
+/// writes to keys starting with its prefix.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+}
+
+impl Watchers {
+    /// Registers a watch of all keys starting with `prefix`. The watch ends
+    /// once the watchers are dropped.
+    pub(crate) fn watch(&self, prefix: Vec<u8>) -> Watch {
+        let (tx, rx) = sync_channel(WATCH_CAPACITY);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.watchers.lock().unwrap_or_else(PoisonError::into_inner).push(Watcher {
+            prefix,
+            tx,
+            lagged: lagged.clone(),
+        });
+
+        Watch {
+            events: rx,
+            lagged,
+            watchers: Arc::downgrade(&self.watchers),
+            ended: false,
+        }
+    }
+
+    /// Hands the event of a write to the given key to all watches of the key,
+    /// dropping watches that are no longer read from or fell behind. The
+    /// event is only built if there is such a watch.
+    pub(crate) fn notify(&self, key: &[u8], event: impl FnOnce() -> WatchEvent) {
+        let mut watchers = self.watchers.lock().unwrap_or_else(PoisonError::into_inner);
+        if !watchers.iter().any(|watcher| key.starts_with(&watcher.prefix)) {
+            return;
+        }
+
+        let event = event();
+        watchers.retain(|watcher| {
+            if !key.starts_with(&watcher.prefix) {
+                return true;
+            }
+
+            match watcher.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    watcher.lagged.store(true, Ordering::SeqCst);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
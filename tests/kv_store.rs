@@ -1,6 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    KvStore, KvStoreError, KvStoreOptions, KvsEngine, Result, SledKvsEngine, SyncPolicy,
+    Version, WatchEvent, WriteBatch, WriteOptions,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,6 +133,89 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should compact in the background on a workload of removes
+#[test]
+fn compaction_on_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_min_bytes(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let value = "v".repeat(1000);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let full_size = dir_size();
+
+    for key_id in 0..1000 {
+        store.remove(format!("key{}", key_id))?;
+        if dir_size() < full_size / 2 {
+            return Ok(());
+        }
+    }
+    for _ in 0..100 {
+        if dir_size() < full_size / 2 {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should keep the number and size of log files bounded under set and remove
+// churn, compacting in the background
+#[test]
+fn compaction_under_churn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4096)
+        .compaction_min_bytes(4096);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    // Files are removed by compaction while walking the directory.
+    let log_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "log"))
+            .filter_map(|e| e.metadata().ok().map(|metadata| metadata.len()))
+            .collect::<Vec<u64>>()
+    };
+    let bounded = || {
+        let files = log_files();
+        files.len() <= 32 && files.iter().sum::<u64>() <= 128 * 1024
+    };
+
+    // About 1.5MB written, of which 100 values of 100 bytes are live.
+    let value = "v".repeat(100);
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        if key_id >= 100 {
+            store.remove(format!("key{}", key_id - 100))?;
+        }
+    }
+
+    for _ in 0..100 {
+        if bounded() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("Log files not bounded: {:?}", log_files());
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -207,5 +296,2093 @@ fn concurrent_get() -> Result<()> {
         handle.join().unwrap();
     }
 
+    // Readers proceed while a writer keeps appending and compacting.
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 0..100 {
+                for key_id in 100..200 {
+                    store
+                        .set(format!("key{}", key_id), format!("{}", iter))
+                        .unwrap();
+                }
+                if iter % 10 == 0 {
+                    store.compact().unwrap();
+                }
+            }
+        })
+    };
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    writer.join().unwrap();
+
+    Ok(())
+}
+
+// Should refuse to open a directory created by a different engine
+#[test]
+fn open_with_wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    match SledKvsEngine::open(temp_dir.path()) {
+        Err(KvStoreError::WrongEngine { .. }) => {}
+        _ => panic!("expected wrong engine error"),
+    }
+
+    // The original engine can still open its directory.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // Directories written before engines were recorded are told apart by
+    // their files, sled writing a `db` file as well.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = r#"{"Set":{"k":"key1","v":"value1"}}"#;
+    std::fs::write(temp_dir.path().join("db"), legacy).expect("unable to write legacy log");
+    match SledKvsEngine::open(temp_dir.path()) {
+        Err(KvStoreError::WrongEngine { .. }) => {}
+        _ => panic!("expected wrong engine error"),
+    }
+    assert_eq!(std::fs::read_to_string(temp_dir.path().join("db")).unwrap(), legacy);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("engine")).expect("unable to remove engine file");
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::WrongEngine { .. }) => {}
+        _ => panic!("expected wrong engine error"),
+    }
+    match KvStore::open_read_only(temp_dir.path()) {
+        Err(KvStoreError::WrongEngine { .. }) => {}
+        _ => panic!("expected wrong engine error"),
+    }
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should split the log into multiple segments and read across all of them
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert!(segments > 1, "expected multiple segments, found {}", segments);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Should adopt the single log file written by earlier versions
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("db"),
+        r#"{"Set":{"k":"key1","v":"value1"}}{"Set":{"k":"key2","v":"value2"}}{"Remove":{"k":"key2"}}"#,
+    )
+    .expect("unable to write legacy log");
+
+    // A store opened read-only replays the legacy log in place.
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert!(temp_dir.path().join("db").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // New records end up next to the legacy ones, compaction rewrites both.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should read segments holding JSON records, as written by earlier versions
+#[test]
+fn open_json_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut segment = b"KVSL".to_vec();
+    segment.extend_from_slice(&1u32.to_le_bytes());
+    for payload in [
+        &br#"{"Set":{"k":"key1","v":"value1"}}"#[..],
+        br#"{"Set":{"k":"key2","v":"value2"}}"#,
+        br#"{"Remove":{"k":"key2"}}"#,
+    ] {
+        segment.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        segment.extend_from_slice(&hasher.finalize().to_le_bytes());
+        segment.extend_from_slice(payload);
+    }
+    std::fs::write(temp_dir.path().join("0.log"), segment).expect("unable to write segment");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // New records end up in a new segment, compaction rewrites both.
+    store.set_bytes(vec![0, 255], vec![159, 146, 150])?;
+    store.compact()?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_bytes(vec![0, 255])?, Some(vec![159, 146, 150]));
+
+    Ok(())
+}
+
+// Should read segments holding bincode records without sequence numbers, as
+// written by earlier versions
+#[test]
+fn open_bincode_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Variant index followed by the length prefixed key and value, and the
+    // absent expiry.
+    let set = |k: &[u8], v: &[u8]| {
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&(k.len() as u64).to_le_bytes());
+        payload.extend_from_slice(k);
+        payload.extend_from_slice(&(v.len() as u64).to_le_bytes());
+        payload.extend_from_slice(v);
+        payload.push(0);
+        payload
+    };
+    let mut remove = 1u32.to_le_bytes().to_vec();
+    remove.extend_from_slice(&4u64.to_le_bytes());
+    remove.extend_from_slice(b"key2");
+
+    let mut segment = b"KVSL".to_vec();
+    segment.extend_from_slice(&2u32.to_le_bytes());
+    for payload in [set(b"key1", b"value1"), set(b"key2", b"value2"), remove] {
+        segment.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&payload);
+        segment.extend_from_slice(&hasher.finalize().to_le_bytes());
+        segment.extend_from_slice(&payload);
+    }
+    std::fs::write(temp_dir.path().join("0.log"), segment).expect("unable to write segment");
+
+    // Records without a sequence number are numbered in log order.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.last_sequence(), 3);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.last_sequence(), 4);
+    store.compact()?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.last_sequence(), 4);
+
+    Ok(())
+}
+
+// Should reclaim space when compacting explicitly, keeping all live data
+#[test]
+fn compact_explicitly() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let size_before = dir_size();
+    store.compact()?;
+    assert!(dir_size() < size_before);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should only compact the segments with the most stale data per run, up to the
+// configured amount of data, keeping removals and history intact
+#[test]
+fn bounded_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .compaction_min_bytes(u64::MAX)
+        .compaction_max_bytes(4096)
+        .history_retention(50);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let segments = || -> Vec<(u64, u64)> {
+        let mut segments: Vec<(u64, u64)> = temp_dir
+            .path()
+            .read_dir()
+            .expect("unable to read directory")
+            .map(|entry| entry.expect("unable to read directory").path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| {
+                let id = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                (id, std::fs::metadata(&path).expect("unable to stat segment").len())
+            })
+            .collect();
+        segments.sort();
+        segments
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            assert_eq!(store.get(format!("key{}", key_id))?, None);
+        }
+        for key_id in 10..90 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("0".to_owned()));
+        }
+        for key_id in 90..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+        }
+        Ok(())
+    };
+
+    // The first segments hold keys written once, the later ones the same keys
+    // written over and over.
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    for iter in 1..20 {
+        for key_id in 90..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // Versions outside of the retained history are dropped at some point.
+    let seq = store.last_sequence();
+    let recent = |store: &KvStore| -> Result<Vec<Version>> {
+        let history = store.history("key95")?;
+        Ok(history.into_iter().filter(|version| version.seq > seq - 50).collect())
+    };
+    let history = recent(&store)?;
+    assert_eq!(history.len(), 5);
+
+    let before = segments();
+    store.compact()?;
+    let after = segments();
+    let compacted: Vec<&(u64, u64)> = before.iter().filter(|segment| !after.contains(segment)).collect();
+    assert!(!compacted.is_empty());
+    assert!(compacted.len() == 1 || compacted.iter().map(|(_, len)| len).sum::<u64>() <= 4096);
+    assert_eq!(after[0], before[0]);
+
+    check(&store)?;
+    assert_eq!(recent(&store)?, history);
+
+    // Compacted segments follow segments holding newer writes.
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    assert_eq!(recent(&store)?, history);
+    assert_eq!(store.last_sequence(), seq);
+
+    for _ in 0..20 {
+        store.compact()?;
+    }
+    assert!(segments().len() < before.len());
+    check(&store)?;
+    assert_eq!(recent(&store)?, history);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+    assert_eq!(recent(&store)?, history);
+    assert_eq!(store.last_sequence(), seq);
+
+    Ok(())
+}
+
+// Should keep accepting writes while compaction is running
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 0..10 {
+                for key_id in 0..100 {
+                    store
+                        .set(format!("key{}", key_id), format!("{}", iter))
+                        .unwrap();
+                }
+            }
+        })
+    };
+    for _ in 0..10 {
+        store.compact()?;
+    }
+    writer.join().unwrap();
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should leave no trace of writes that failed to be appended to the log
+#[cfg(unix)]
+#[test]
+fn failed_writes() -> Result<()> {
+    // Concurrent writes are appended in groups, of which one fails halfway
+    // in some of the rounds.
+    for _ in 0..20 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().max_segment_size(4096);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+
+        // Rotating to the next segment fails, and so do the writes that do
+        // not fit into the active segment anymore, until the failed segment
+        // is cleaned up.
+        let active = last_segment(temp_dir.path());
+        let id: u64 = active.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+        std::os::unix::fs::symlink("/dev/full", temp_dir.path().join(format!("{}.log", id + 1))).unwrap();
+
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    (0..10)
+                        .map(|key_id| {
+                            let key = format!("key{}-{}", thread_id, key_id);
+                            let committed = store.set(key.clone(), "v".repeat(100)).is_ok();
+                            (key, committed)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let writes: Vec<(String, bool)> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+
+        assert!(writes.iter().any(|(_, committed)| !committed));
+        let committed = writes.iter().filter(|(_, committed)| *committed).count() as u64;
+        assert_eq!(store.last_sequence(), committed + 1);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for (key, committed) in &writes {
+            assert_eq!(store.get(key.clone())?.is_some(), *committed, "{}", key);
+        }
+        assert_eq!(store.last_sequence(), committed + 1);
+    }
+
+    Ok(())
+}
+
+// Returns the path of the log segment with the highest id.
+fn last_segment(dir: &Path) -> PathBuf {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_path_buf())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "log"))
+        .max_by_key(|p| {
+            p.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+        })
+        .expect("no log segment found")
+}
+
+// Should detect a corrupted record when reading it, without affecting others
+#[test]
+fn read_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Flip the last byte of the first record.
+    let segment = last_segment(temp_dir.path());
+    let mut content = std::fs::read(&segment).expect("unable to read segment");
+    let first_value = content
+        .windows(6)
+        .position(|w| w == b"value1")
+        .expect("value not found");
+    content[first_value] ^= 0xff;
+    std::fs::write(&segment, &content).expect("unable to write segment");
+
+    match store.get("key1".to_owned()) {
+        Err(KvStoreError::CorruptRecord { .. }) => {}
+        _ => panic!("expected corrupt record error"),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Corruption within the log is not mistaken for a torn write.
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptRecord { .. }) => {}
+        _ => panic!("expected corrupt record error"),
+    }
+
+    Ok(())
+}
+
+// Should cut off a write torn at any byte of the last record and open the store
+#[test]
+fn recover_torn_write() -> Result<()> {
+    fn write(dir: &Path) -> Result<(u64, u64)> {
+        let store = KvStore::open(dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        let start = std::fs::metadata(last_segment(dir))
+            .expect("unable to stat segment")
+            .len();
+
+        let store = KvStore::open(dir)?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let end = std::fs::metadata(last_segment(dir))
+            .expect("unable to stat segment")
+            .len();
+
+        Ok((start, end))
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (start, end) = write(temp_dir.path())?;
+    assert!(start < end);
+
+    for len in start..end {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        write(temp_dir.path())?;
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(last_segment(temp_dir.path()))
+            .and_then(|f| f.set_len(len))
+            .expect("unable to truncate segment");
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should recover a segment torn within its header
+#[test]
+fn recover_torn_segment_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = last_segment(temp_dir.path());
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&segment)
+        .and_then(|f| f.set_len(3))
+        .expect("unable to truncate segment");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should open the store after the process died at any point of a compaction
+// run, dropping the segments it left behind
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+            .expect("unable to read directory")
+            .map(|e| e.expect("unable to read directory").path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn copy(files: &[PathBuf], dir: &Path) {
+        for file in files {
+            std::fs::copy(file, dir.join(file.file_name().unwrap())).expect("unable to copy file");
+        }
+    }
+
+    fn check(dir: &Path, options: &KvStoreOptions) -> Result<()> {
+        let store = KvStore::open_with(dir, options.clone())?;
+        for key_id in 0..50 {
+            let value = if key_id % 5 == 0 { None } else { Some("2".to_owned()) };
+            assert_eq!(store.get(format!("key{}", key_id))?, value);
+        }
+        store.set("key0".to_owned(), "3".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let store = KvStore::open_with(dir, options.clone())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("3".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("2".to_owned()));
+        assert_eq!(store.get("key5".to_owned())?, None);
+
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .compaction_min_bytes(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in (0..50).step_by(5) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+
+    let before_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy(&files(temp_dir.path()), before_dir.path());
+    let before = files(before_dir.path());
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.compact()?;
+    drop(store);
+
+    // Compacted segments are written one after the other, between the
+    // segments being compacted and the new active segment.
+    let outputs: Vec<PathBuf> = files(temp_dir.path())
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|ext| ext == "hint"))
+        .map(|p| p.with_extension("log"))
+        .collect();
+    let active = last_segment(temp_dir.path());
+    assert!(outputs.len() > 1);
+
+    for (i, output) in outputs.iter().enumerate() {
+        let len = std::fs::metadata(output).expect("unable to stat segment").len();
+        // Cut within the header and first record of the first segment, and
+        // all along the others.
+        for cut in (0..=len).filter(|cut| (i == 0 && *cut < 48) || cut % 128 == 0 || *cut == len) {
+            let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+            copy(&before, crash_dir.path());
+            copy(std::slice::from_ref(&active), crash_dir.path());
+            for done in &outputs[..i] {
+                copy(&[done.clone(), done.with_extension("hint")], crash_dir.path());
+            }
+            let torn = crash_dir.path().join(output.file_name().unwrap());
+            let content = std::fs::read(output).expect("unable to read segment");
+            std::fs::write(&torn, &content[..cut as usize]).expect("unable to write segment");
+
+            check(crash_dir.path(), &options)?;
+        }
+    }
+
+    // The process died after all compacted segments were complete, but before
+    // the compacted segments were removed.
+    let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+    copy(&before, crash_dir.path());
+    copy(&files(temp_dir.path()), crash_dir.path());
+    check(crash_dir.path(), &options)?;
+
+    Ok(())
+}
+
+// Should persist writes under every sync policy and with per write syncs
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        SyncPolicy::Bytes(64),
+    ];
+
+    for policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .max_segment_size(256)
+            .sync_policy(*policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.set_with(
+            "synced".to_owned(),
+            "value".to_owned(),
+            &WriteOptions::new().sync(true),
+        )?;
+        store.remove_with("key0".to_owned(), &WriteOptions::new().sync(true))?;
+        thread::sleep(Duration::from_millis(20));
+        store.sync()?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("synced".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should commit concurrent writes in groups, failing only the removes of
+// missing keys
+#[test]
+fn concurrent_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let barrier = Arc::new(Barrier::new(100));
+    let handles: Vec<_> = (0..100)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    store.set(key.clone(), format!("value{}", i)).unwrap();
+                    if i % 2 == 0 {
+                        store.remove(key.clone()).unwrap();
+                        match store.remove(key) {
+                            Err(KvStoreError::KeyNotFound) => {}
+                            _ => panic!("expected key not found error"),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..100 {
+            for i in 0..20 {
+                let expected = if i % 2 == 0 {
+                    None
+                } else {
+                    Some(format!("value{}", i))
+                };
+                assert_eq!(store.get(format!("key{}-{}", thread_id, i))?, expected);
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Should iterate over keys and key ranges in ascending order on both engines
+#[test]
+fn scan_keys() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+        for i in (0..50).rev() {
+            store.set(format!("key{:02}", i), format!("value{}", i))?;
+        }
+        store.remove("key10".to_owned())?;
+
+        let keys = store.keys()?.collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (0..50)
+            .filter(|i| *i != 10)
+            .map(|i| format!("key{:02}", i))
+            .collect();
+        assert_eq!(keys, expected);
+
+        let pairs = store
+            .scan(Some("key08".to_owned()), Some("key13".to_owned()), None)?
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = [8, 9, 11, 12]
+            .iter()
+            .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        let pairs = store
+            .scan(Some("key45".to_owned()), None, Some(3))?
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (45..48)
+            .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        assert_eq!(store.scan(Some("zzz".to_owned()), None, None)?.count(), 0);
+
+        let pairs = store
+            .scan_prefix("key1".to_owned())?
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (11..20)
+            .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        assert_eq!(store.scan_prefix("key5".to_owned())?.count(), 0);
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should apply all writes of a batch, or none if one of them fails
+#[test]
+fn write_batch() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch.set("key2".to_owned(), "value2".to_owned());
+        batch.remove("key1".to_owned());
+        batch.set("key3".to_owned(), "value3".to_owned());
+        batch.remove("key3".to_owned());
+        store.write_batch(batch)?;
+
+        let mut batch = WriteBatch::new();
+        batch.set("key4".to_owned(), "value4".to_owned());
+        batch.remove("key2".to_owned());
+        batch.remove("key2".to_owned());
+        match store.write_batch(batch) {
+            Err(KvStoreError::KeyNotFound) => {}
+            _ => panic!("expected key not found error"),
+        }
+
+        let check = |store: &E| -> Result<()> {
+            assert_eq!(store.get("key1".to_owned())?, None);
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(store.get("key3".to_owned())?, None);
+            assert_eq!(store.get("key4".to_owned())?, None);
+            Ok(())
+        };
+        check(&store)?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        check(&store)
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should only apply a batch if all of its expectations hold
+#[test]
+fn write_batch_expectations() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch.expect("key1", Some(b"value1".to_vec()));
+        batch.expect("key2", Some(b"value2".to_vec()));
+        batch.set("key3", "value3");
+        match store.write_batch(batch) {
+            Err(KvStoreError::ConditionFailed) => {}
+            _ => panic!("expected condition failed error"),
+        }
+        assert_eq!(store.get("key3".to_owned())?, None);
+
+        let mut batch = WriteBatch::new();
+        batch.expect("key1", Some(b"value1".to_vec()));
+        batch.expect("key2", None);
+        batch.set("key3", "value3");
+        batch.remove("key1");
+        store.write_batch(batch)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+        // Expectations are checked even without any writes.
+        let mut batch = WriteBatch::new();
+        batch.expect("key3", None);
+        match store.write_batch(batch) {
+            Err(KvStoreError::ConditionFailed) => {}
+            _ => panic!("expected condition failed error"),
+        }
+        let mut batch = WriteBatch::new();
+        batch.expect("key3", Some(b"value3".to_vec()));
+        store.write_batch(batch)?;
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should drop a batch torn at any byte, keeping earlier writes
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    fn write(dir: &Path) -> Result<(u64, u64)> {
+        let store = KvStore::open(dir)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        let start = std::fs::metadata(last_segment(dir)).expect("unable to stat segment").len();
+
+        let store = KvStore::open(dir)?;
+        let mut batch = WriteBatch::new();
+        batch.remove("key1".to_owned());
+        batch.set("key2".to_owned(), "value2".to_owned());
+        batch.set("key3".to_owned(), "value3".to_owned());
+        store.write_batch(batch)?;
+        drop(store);
+        let end = std::fs::metadata(last_segment(dir)).expect("unable to stat segment").len();
+
+        Ok((start, end))
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (start, end) = write(temp_dir.path())?;
+
+    for len in start..end {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        write(temp_dir.path())?;
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(last_segment(temp_dir.path()))
+            .and_then(|f| f.set_len(len))
+            .expect("unable to truncate segment");
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Should only apply conditional writes whose condition holds
+#[test]
+fn compare_and_swap() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        store.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+        match store.set_if_absent("key1".to_owned(), "value2".to_owned()) {
+            Err(KvStoreError::ConditionFailed) => {}
+            _ => panic!("expected condition failed error"),
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+        )?;
+        match store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+        ) {
+            Err(KvStoreError::ConditionFailed) => {}
+            _ => panic!("expected condition failed error"),
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        store.compare_and_swap("key1".to_owned(), None, None)?;
+        match store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None) {
+            Err(KvStoreError::ConditionFailed) => {}
+            _ => panic!("expected condition failed error"),
+        }
+
+        store.set_if_absent("key2".to_owned(), "value2".to_owned())?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should not lose any update of concurrent compare and swap loops
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned()).unwrap();
+                        let next = current.as_ref().map_or(0, |c| c.parse::<u64>().unwrap()) + 1;
+                        match store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next.to_string()),
+                        ) {
+                            Ok(()) => break,
+                            Err(KvStoreError::ConditionFailed) => continue,
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Should add to numbers, creating missing keys and rejecting other values
+#[test]
+fn increment() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        assert_eq!(store.increment("counter".to_owned(), 1)?, 1);
+        assert_eq!(store.increment("counter".to_owned(), 41)?, 42);
+        assert_eq!(store.increment("counter".to_owned(), -50)?, -8);
+        assert_eq!(store.get("counter".to_owned())?, Some("-8".to_owned()));
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        match store.increment("key1".to_owned(), 1) {
+            Err(KvStoreError::NotANumber { .. }) => {}
+            _ => panic!("expected not a number error"),
+        }
+
+        store.set("max".to_owned(), i64::MAX.to_string())?;
+        match store.increment("max".to_owned(), 1) {
+            Err(KvStoreError::NumberOverflow { .. }) => {}
+            _ => panic!("expected number overflow error"),
+        }
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.increment("counter".to_owned(), 0)?, -8);
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should not lose any increment of concurrent writers
+#[test]
+fn concurrent_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..50 {
+                    store.increment("counter".to_owned(), 2).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+// Should hide keys once expired
+#[test]
+fn expire_keys() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(500))?;
+        store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+        store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(500))?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // Swaps keep the expiry of the value they replace.
+        store.set_with_ttl("key4".to_owned(), "1".to_owned(), Duration::from_millis(500))?;
+        assert_eq!(store.increment("key4".to_owned(), 1)?, 2);
+        store.compare_and_swap("key4".to_owned(), Some("2".to_owned()), Some("3".to_owned()))?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, Some("3".to_owned()));
+
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        let keys: Vec<String> = store.keys()?.collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["key2".to_owned(), "key3".to_owned()]);
+
+        match store.remove("key1".to_owned()) {
+            Err(KvStoreError::KeyNotFound) => {}
+            _ => panic!("expected key not found error"),
+        }
+        store.set_if_absent("key1".to_owned(), "value4".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+        match store.set_with_ttl("key1".to_owned(), "value5".to_owned(), Duration::MAX) {
+            Err(KvStoreError::TtlOverflow { .. }) => {}
+            _ => panic!("expected ttl overflow error"),
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should keep a value and its expiry together under concurrent writes
+#[test]
+fn concurrent_expire_keys() -> Result<()> {
+    fn check<E: KvsEngine + Sync>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        for key_id in 0..200 {
+            let key = format!("key{}", key_id);
+            let barrier = Arc::new(Barrier::new(2));
+            let handle = {
+                let (store, key, barrier) = (store.clone(), key.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.set(key, "value".to_owned()).unwrap();
+                })
+            };
+            barrier.wait();
+            store.set_with_ttl(key, "expiring".to_owned(), Duration::from_millis(100))?;
+            handle.join().unwrap();
+        }
+
+        // Whichever write came last, the expiring value never outlives its
+        // expiry.
+        thread::sleep(Duration::from_millis(200));
+        for key_id in 0..200 {
+            assert_ne!(store.get(format!("key{}", key_id))?, Some("expiring".to_owned()));
+        }
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should drop expired keys from the log
+#[test]
+fn compact_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    for key_id in 0..100 {
+        store.set_with_ttl(format!("key{}", key_id), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    store.set("key100".to_owned(), "value".to_owned())?;
+
+    // Leave time for the expired keys to be reaped.
+    thread::sleep(Duration::from_millis(1500));
+    let size_before = dir_size();
+    store.compact()?;
+    assert!(dir_size() < size_before);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let keys: Vec<String> = store.keys()?.collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key100".to_owned()]);
+
+    Ok(())
+}
+
+// Should store keys and values that are not valid utf8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        store.set_bytes(vec![0, 255, 1], vec![159, 146, 150, 0])?;
+        store.set_bytes(vec![255, 255], vec![])?;
+        store.set_bytes(vec![255, 255, 0], b"value".to_vec())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get_bytes(vec![0, 255, 1])?, Some(vec![159, 146, 150, 0]));
+        assert_eq!(store.get_bytes(vec![255, 255])?, Some(vec![]));
+        assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+        // The string layer refuses bytes that are not utf8.
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set_bytes(b"key2".to_vec(), vec![159, 146, 150])?;
+        match store.get("key2".to_owned()) {
+            Err(KvStoreError::Utf8Failure { .. }) => {}
+            _ => panic!("expected utf8 failure"),
+        }
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = store.scan_prefix_bytes(vec![255, 255])?.collect::<Result<_>>()?;
+        assert_eq!(
+            pairs,
+            vec![(vec![255, 255], vec![]), (vec![255, 255, 0], b"value".to_vec())]
+        );
+        let keys: Vec<Vec<u8>> = store.keys_bytes()?.collect::<Result<_>>()?;
+        assert_eq!(
+            keys,
+            vec![
+                vec![0, 255, 1],
+                b"key1".to_vec(),
+                b"key2".to_vec(),
+                vec![255, 255],
+                vec![255, 255, 0],
+            ]
+        );
+
+        store.compare_and_swap_bytes(vec![0, 255, 1], Some(vec![159, 146, 150, 0]), Some(vec![1]))?;
+        store.remove_bytes(vec![255, 255])?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = E::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(vec![0, 255, 1])?, Some(vec![1]));
+        assert_eq!(store.get_bytes(vec![255, 255])?, None);
+        assert_eq!(store.get_bytes(vec![255, 255, 0])?, Some(b"value".to_vec()));
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should read the values as of the time a snapshot was taken
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let first = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value5");
+    batch.set("key1", "value6");
+    store.write_batch(batch)?;
+
+    let second = store.snapshot();
+    store.set("key1".to_owned(), "value7".to_owned())?;
+
+    assert_eq!(first.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(first.get("key3".to_owned())?, None);
+    assert_eq!(second.get("key1".to_owned())?, Some("value6".to_owned()));
+    assert_eq!(second.get("key2".to_owned())?, None);
+    assert_eq!(second.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value7".to_owned()));
+
+    drop(first);
+    assert_eq!(second.get("key1".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the versions seen by a snapshot across compaction
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum::<u64>()
+    };
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let snapshot = store.snapshot();
+    for iter in 1..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+
+    for key_id in 0..100 {
+        assert_eq!(snapshot.get(format!("key{}", key_id))?, Some("0".to_owned()));
+    }
+
+    // Versions only kept for the snapshot are dropped by the next compaction.
+    let size = dir_size();
+    drop(snapshot);
+    store.compact()?;
+    assert!(dir_size() < size);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should read within a transaction as of its start plus its own writes
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut runs = 0;
+    let value = store.transaction(|tx| {
+        runs += 1;
+        let value = tx.get("key1".to_owned())?;
+        // Conflicting write, only on the first run.
+        if runs == 1 {
+            store.set("key1".to_owned(), "value3".to_owned())?;
+        }
+        assert_eq!(tx.get("key1".to_owned())?, value);
+
+        tx.set("key3".to_owned(), "value4".to_owned());
+        assert_eq!(tx.get("key3".to_owned())?, Some("value4".to_owned()));
+        tx.remove("key2".to_owned())?;
+        assert_eq!(tx.get("key2".to_owned())?, None);
+        Ok(value)
+    })?;
+    assert_eq!(runs, 2);
+    assert_eq!(value, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    // An error aborts the transaction without writing anything.
+    let result = store.transaction(|tx| {
+        tx.set("key4".to_owned(), "value5".to_owned());
+        tx.remove("key2".to_owned())
+    });
+    match result {
+        Err(KvStoreError::KeyNotFound) => {}
+        _ => panic!("expected key not found error"),
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should keep the sum of all accounts across concurrent transfers
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for account in 0..4 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..25 {
+                    let from = format!("account{}", (thread_id + i) % 4);
+                    let to = format!("account{}", (thread_id + i + 1) % 4);
+                    store
+                        .transaction(|tx| {
+                            let balance = |v: Option<String>| v.unwrap().parse::<i64>().unwrap();
+                            let from_balance = balance(tx.get(from.clone())?);
+                            let to_balance = balance(tx.get(to.clone())?);
+                            tx.set(from.clone(), (from_balance - 1).to_string());
+                            tx.set(to.clone(), (to_balance + 1).to_string());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut sum = 0;
+    for account in 0..4 {
+        sum += store.get(format!("account{}", account))?.unwrap().parse::<i64>().unwrap();
+    }
+    assert_eq!(sum, 400);
+
+    Ok(())
+}
+
+// Should list and read previous versions of a key within the retained history
+#[test]
+fn key_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().history_retention(100);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+
+    let values = |store: &KvStore| -> Result<Vec<Option<Vec<u8>>>> {
+        Ok(store.history("key1")?.into_iter().map(|version| version.value).collect())
+    };
+    let expected = vec![
+        Some(b"value1".to_vec()),
+        Some(b"value3".to_vec()),
+        None,
+        Some(b"value4".to_vec()),
+    ];
+
+    let history = store.history("key1")?;
+    assert_eq!(values(&store)?, expected);
+    assert!(history.windows(2).all(|w| w[0].seq < w[1].seq));
+    for version in &history {
+        assert_eq!(store.get_at("key1", version.seq)?, version.value);
+    }
+    assert_eq!(store.get_at("key1", history[0].seq - 1)?, None);
+    assert_eq!(store.get_at("key2", history[0].seq)?, None);
+    assert_eq!(store.get_at("key2", history[1].seq)?, Some(b"value2".to_vec()));
+    assert_eq!(store.history("key3")?, vec![]);
+
+    // Compaction and replay carry the history over.
+    store.compact()?;
+    assert_eq!(values(&store)?, expected);
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(values(&store)?, expected);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    // A removed key keeps its history, ending with the removal.
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    let history = store.history("key2")?;
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].value, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should only keep the versions superseded by the most recent writes
+#[test]
+fn history_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().history_retention(10);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for iter in 0..30 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let first = store.history("key1")?[0].seq;
+
+    // Opening the store prunes versions outside of the retention window.
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 11);
+    assert_eq!(history[0].value, Some(b"value19".to_vec()));
+    assert_eq!(store.get_at("key1", history[0].seq)?, Some(b"value19".to_vec()));
+    match store.get_at("key1", first) {
+        Err(KvStoreError::HistoryUnavailable { .. }) => {}
+        _ => panic!("expected history unavailable error"),
+    }
+
+    // Without retained history only the current version is readable.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(store.get_at("key1", history[0].seq)?, Some(b"value2".to_vec()));
+    match store.get_at("key1", history[0].seq - 1) {
+        Err(KvStoreError::HistoryUnavailable { .. }) => {}
+        _ => panic!("expected history unavailable error"),
+    }
+
+    Ok(())
+}
+
+// Should number writes in commit order, keeping the numbers across restarts
+// and compaction
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system time before unix epoch")
+            .as_millis() as u64
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().history_retention(100);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.last_sequence(), 0);
+
+    let start = now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.last_sequence(), 1);
+
+    // All writes of a batch share a sequence number.
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value2");
+    batch.set("key2", "value3");
+    store.write_batch(batch)?;
+    assert_eq!(store.last_sequence(), 2);
+
+    // Failed writes do not get a sequence number.
+    assert!(store.remove("key3".to_owned()).is_err());
+    assert_eq!(store.last_sequence(), 2);
+
+    // Neither do batches only checking their expectations.
+    let mut batch = WriteBatch::new();
+    batch.expect("key2", Some(b"value3".to_vec()));
+    store.write_batch(batch)?;
+    assert_eq!(store.last_sequence(), 2);
+
+    store.remove("key1".to_owned())?;
+    let history = store.history("key1")?;
+    assert_eq!(history.iter().map(|version| version.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+    // Values come with the time of their write.
+    let end = now();
+    assert!(history[..2]
+        .iter()
+        .all(|version| version.time.is_some_and(|time| start <= time && time <= end)));
+    assert_eq!(history[2].time, None);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.last_sequence(), 3);
+    assert_eq!(store.history("key1")?, history);
+
+    // Compaction dropping the latest writes keeps the sequence number.
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.last_sequence(), 4);
+    assert_eq!(store.history("key1")?, vec![]);
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.last_sequence(), 5);
+
+    Ok(())
+}
+
+// Should see the writes to watched keys in commit order
+#[test]
+fn watch() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<Vec<WatchEvent>> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value0".to_owned())?;
+
+        let watch = store.watch(b"key".to_vec())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("other".to_owned(), "value".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2");
+        batch.remove("key1");
+        store.write_batch(batch)?;
+        assert!(store.remove("key3".to_owned()).is_err());
+        store.remove("key2".to_owned())?;
+
+        let events = watch.take(4).collect::<Result<Vec<_>>>()?;
+        let strip = |event: &WatchEvent| match event.clone() {
+            WatchEvent::Set { key, value, .. } => WatchEvent::Set { key, value, seq: None, time: None },
+            WatchEvent::Remove { key, .. } => WatchEvent::Remove { key, seq: None, time: None },
+        };
+        assert_eq!(
+            events.iter().map(strip).collect::<Vec<_>>(),
+            vec![
+                WatchEvent::Set { key: b"key1".to_vec(), value: b"value1".to_vec(), seq: None, time: None },
+                WatchEvent::Set { key: b"key2".to_vec(), value: b"value2".to_vec(), seq: None, time: None },
+                WatchEvent::Remove { key: b"key1".to_vec(), seq: None, time: None },
+                WatchEvent::Remove { key: b"key2".to_vec(), seq: None, time: None },
+            ]
+        );
+
+        Ok(events)
+    }
+
+    let seqs = check::<KvStore>()?
+        .into_iter()
+        .map(|event| match event {
+            WatchEvent::Set { seq, .. } | WatchEvent::Remove { seq, .. } => seq,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(seqs, vec![Some(2), Some(4), Some(4), Some(5)]);
+    check::<SledKvsEngine>()?;
+
+    // A write is seen by readers once its watches see it.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watch = store.watch(b"key".to_vec())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                store.set("key1".to_owned(), format!("{}", i)).unwrap();
+            }
+        })
+    };
+    for event in watch.take(100) {
+        let written: u64 = match event? {
+            WatchEvent::Set { value, .. } => String::from_utf8(value).unwrap().parse().unwrap(),
+            WatchEvent::Remove { .. } => panic!("unexpected remove"),
+        };
+        let read: u64 = store.get("key1".to_owned())?.unwrap().parse().unwrap();
+        assert!(read >= written);
+    }
+    writer.join().unwrap();
+
+    Ok(())
+}
+
+// Should end watches that fall too far behind, without holding up writes
+#[test]
+fn watch_lagged() -> Result<()> {
+    fn check<E: KvsEngine>() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = E::open(temp_dir.path())?;
+
+        let mut watch = store.watch(b"key".to_vec())?;
+        let dropped = store.watch(b"key".to_vec())?;
+        drop(dropped);
+        for i in 0..2000 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+
+        // Writes buffered before falling behind are still seen.
+        let mut seen = 0;
+        let lagged = loop {
+            match watch.recv_timeout(Duration::from_secs(1)) {
+                Ok(Ok(_)) => seen += 1,
+                Ok(Err(e)) => break e,
+                Err(e) => panic!("expected lagged error, got {:?}", e),
+            }
+        };
+        match lagged {
+            KvStoreError::WatchLagged => {}
+            e => panic!("expected lagged error, got {}", e),
+        }
+        assert!(seen > 0 && seen < 2000);
+        assert!(watch.next().is_none());
+
+        Ok(())
+    }
+
+    check::<KvStore>()?;
+    check::<SledKvsEngine>()
+}
+
+// Should open compacted segments from their hint files, replaying segments
+// whose hint file is missing or corrupt instead
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().history_retention(100);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    let history = store.history("key1")?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.history("key1")?, history);
+        assert_eq!(store.get_at("key2", 3)?, Some(b"value3".to_vec()));
+        assert_eq!(store.last_sequence(), 5);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let hints = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().to_path_buf())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "hint"))
+            .collect::<Vec<_>>()
+    };
+    let hint = &hints()[0];
+    assert_eq!(hints().len(), 1);
+    let segment = hint.with_extension("log");
+
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    // The compacted segment is not read on open given its hint.
+    let mut content = std::fs::read(&segment).expect("unable to read segment");
+    let value = content
+        .windows(6)
+        .position(|w| w == b"value3")
+        .expect("value not found");
+    content[value] ^= 0xff;
+    std::fs::write(&segment, &content).expect("unable to write segment");
+    KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    // A corrupt hint file is ignored.
+    let mut hint_content = std::fs::read(hint).expect("unable to read hint");
+    let last = hint_content.len() - 1;
+    hint_content[last] ^= 0xff;
+    std::fs::write(hint, &hint_content).expect("unable to write hint");
+    match KvStore::open_with(temp_dir.path(), options.clone()) {
+        Err(KvStoreError::CorruptRecord { .. }) => {}
+        _ => panic!("expected corrupt record error"),
+    }
+    content[value] ^= 0xff;
+    std::fs::write(&segment, &content).expect("unable to write segment");
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    // So is a missing one.
+    std::fs::remove_file(hint).expect("unable to remove hint");
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    // Compaction replaces hint files along with their segments.
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    check(&store)?;
+    assert!(!segment.exists());
+    assert_eq!(hints().len(), 1);
+
+    Ok(())
+}
+
+// Should refuse to open a directory opened by another store, unless read-only
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::DirectoryLocked { .. }) => {}
+        _ => panic!("expected directory locked error"),
+    }
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    match reader.set("key1".to_owned(), "value2".to_owned()) {
+        Err(KvStoreError::ReadOnly) => {}
+        _ => panic!("expected read-only error"),
+    }
+    match reader.remove("key1".to_owned()) {
+        Err(KvStoreError::ReadOnly) => {}
+        _ => panic!("expected read-only error"),
+    }
+    match reader.compact() {
+        Err(KvStoreError::ReadOnly) => {}
+        _ => panic!("expected read-only error"),
+    }
+
+    // The lock is released once the store is dropped.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // A read-only store leaves a torn write in place.
+    let segment = last_segment(temp_dir.path());
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .expect("unable to open segment");
+    file.write_all(&[1, 2, 3]).expect("unable to write segment");
+    let len = std::fs::metadata(&segment).expect("unable to stat segment").len();
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(std::fs::metadata(&segment).expect("unable to stat segment").len(), len);
+
+    Ok(())
+}
+
+// Should catch up with the writes of a writer, including across compactions,
+// without modifying the directory
+#[test]
+fn follow_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Nothing is created in an empty directory.
+    KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(temp_dir.path().read_dir().expect("unable to read directory").count(), 0);
+
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    let follower = KvStore::open_read_only_with(temp_dir.path(), KvStoreOptions::new().follow(Duration::from_millis(10)))?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key0", "1");
+    batch.remove("key1");
+    store.write_batch(batch)?;
+    assert_eq!(reader.get("key0".to_owned())?, None);
+
+    reader.refresh()?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("1".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key99".to_owned())?, Some("0".to_owned()));
+    assert_eq!(reader.last_sequence(), store.last_sequence());
+
+    store.compact()?;
+    store.set("key2".to_owned(), "1".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("1".to_owned()));
+    assert_eq!(reader.keys()?.count(), 99);
+    assert_eq!(reader.last_sequence(), store.last_sequence());
+
+    // Readers never see a key go back to an older value.
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 2..20 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+                if iter % 5 == 0 {
+                    store.compact()?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let mut seen = vec![0; 100];
+    while !writer.is_finished() {
+        reader.refresh()?;
+        for (key_id, seen) in seen.iter_mut().enumerate() {
+            if let Some(value) = reader.get(format!("key{}", key_id))? {
+                let value = value.parse::<u64>().unwrap();
+                assert!(value >= *seen);
+                *seen = value;
+            }
+        }
+    }
+    writer.join().unwrap()?;
+
+    reader.refresh()?;
+    for key_id in 0..100 {
+        assert_eq!(reader.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    // The follower catches up on its own.
+    for _ in 0..500 {
+        if follower.get("key99".to_owned())? == Some("19".to_owned()) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(follower.get("key99".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// Should see all live keys when opened read-only while the writer compacts
+#[test]
+fn open_read_only_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..50 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+                store.compact()?;
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let reader = KvStore::open_read_only(temp_dir.path())?;
+        for key_id in 0..100 {
+            assert!(reader.get(format!("key{}", key_id))?.is_some(), "key{}", key_id);
+        }
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+// Should validate options and apply them
+#[test]
+fn store_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let invalid = vec![
+        KvStoreOptions::new().max_segment_size(0),
+        KvStoreOptions::new().compaction_ratio(-1.0),
+        KvStoreOptions::new().compaction_ratio(f64::NAN),
+        KvStoreOptions::new().max_key_size(0),
+        KvStoreOptions::new().max_value_size(0),
+        KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_secs(0))),
+        KvStoreOptions::new().create_if_missing(false).error_if_exists(true),
+    ];
+    for options in invalid {
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvStoreError::InvalidOptions { .. }) => {}
+            _ => panic!("expected invalid options error"),
+        }
+    }
+
+    let path = temp_dir.path().join("store");
+    match KvStore::open_with(&path, KvStoreOptions::new().create_if_missing(false)) {
+        Err(KvStoreError::StoreNotFound { .. }) => {}
+        _ => panic!("expected store not found error"),
+    }
+    assert!(!path.exists());
+
+    let store = KvStore::open_with(&path, KvStoreOptions::new().error_if_exists(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    match KvStore::open_with(&path, KvStoreOptions::new().error_if_exists(true)) {
+        Err(KvStoreError::StoreExists { .. }) => {}
+        _ => panic!("expected store exists error"),
+    }
+
+    let options = KvStoreOptions::new()
+        .create_if_missing(false)
+        .max_key_size(4)
+        .max_value_size(6)
+        .max_segment_size(1024)
+        .compaction_ratio(0.0)
+        .compaction_min_bytes(0);
+    let store = KvStore::open_with(&path, options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    match store.set("key10".to_owned(), "value".to_owned()) {
+        Err(KvStoreError::KeyTooLarge { len: 5, max: 4 }) => {}
+        _ => panic!("expected key too large error"),
+    }
+    match store.remove("key10".to_owned()) {
+        Err(KvStoreError::KeyTooLarge { .. }) => {}
+        _ => panic!("expected key too large error"),
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2");
+    batch.set("key3", "value10");
+    match store.write_batch(batch) {
+        Err(KvStoreError::ValueTooLarge { len: 7, max: 6 }) => {}
+        _ => panic!("expected value too large error"),
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Any stale record is compacted away.
+    let segments = || {
+        path.read_dir()
+            .expect("unable to read directory")
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    let mut compacted = false;
+    for _ in 0..500 {
+        if segments() <= 2 {
+            compacted = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(compacted);
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+
     Ok(())
 }
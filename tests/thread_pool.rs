@@ -1,12 +1,9 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
 use kvs::thread_pool::*;
 use kvs::Result;
 
-use crossbeam_utils::sync::WaitGroup;
-
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
     const ADD_COUNT: usize = 1000;